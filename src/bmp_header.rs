@@ -1,5 +1,10 @@
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{self,Read,Seek,SeekFrom};
+use color_space::{ColorSpace,ProfileLocation,RenderingIntent};
+#[cfg(feature = "png")]
+use png;
+use std::error::Error;
+use std::fmt;
+use std::io::{self,Read};
 
 const BMP_BITFIELD32_RED: u32   = 0x00ff0000;
 const BMP_BITFIELD32_GREEN: u32 = 0x0000ff00;
@@ -8,6 +13,7 @@ const BMP_BITFIELD16_RED: u16   = 0b0111110000000000;
 const BMP_BITFIELD16_GREEN: u16 = 0b0000001111100000;
 const BMP_BITFIELD16_BLUE: u16  = 0b0000000000011111;
 
+#[derive(Debug)]
 pub enum BMPError {
     WrongMagicNumbers(u8, u8),
     UnsupportedHeaderSize(u32),
@@ -20,6 +26,23 @@ pub enum BMPError {
     InvalidWidth(i32),
     InvalidHeight(i32),
     HeaderTooLarge(u64, u64),
+    CompressionNotSupportedForPixelDepth(CompressionType, u16),
+    BufferTooSmall(usize, usize),
+    ImageTooLarge { width: u32, height: u32 },
+    TruncatedPixelData { row: usize, column: usize },
+    InvalidHuffmanCode { row: usize, column: usize },
+    PaletteIndexOutOfRange { index: usize, palette_len: usize },
+    UnsupportedWriterVersion(BMPVersion),
+    PaletteTooLarge(usize, u16),
+    UnsupportedWriterCompression(CompressionType),
+    CompressionNotSupportedForTopDown(CompressionType),
+    ProfileOverlapsPixelData { offset: u64, size: u64 },
+    PixelDataSizeUnknown(CompressionType),
+    FileSizeMismatch { declared: u64, actual: u64 },
+    EmbeddedImageNotSupported(EmbeddedFormat),
+    EmbeddedImageSizeMismatch { width: u32, height: u32, header_width: u32, header_height: u32 },
+    #[cfg(feature = "png")]
+    PNGError(png::DecodingError),
     IOError(io::Error),
 }
 
@@ -29,29 +52,171 @@ impl From<io::Error> for BMPError {
     }
 }
 
-enum CompressionType {
+#[cfg(feature = "png")]
+impl From<png::DecodingError> for BMPError {
+    fn from(err: png::DecodingError) -> BMPError {
+        BMPError::PNGError(err)
+    }
+}
+
+const MASK_NAMES: [&str; 4] = ["red", "green", "blue", "alpha"];
+
+impl fmt::Display for BMPError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BMPError::WrongMagicNumbers(first, second) =>
+                write!(f, "wrong magic numbers {:#04x} {:#04x}, expected \"BM\"", first, second),
+            BMPError::UnsupportedHeaderSize(size) =>
+                write!(f, "unsupported DIB header size of {} bytes", size),
+            BMPError::UnsupportedNumberOfPlanes(planes) =>
+                write!(f, "unsupported number of planes {}, expected 1", planes),
+            BMPError::UnsupportedCompressionType(compression) =>
+                write!(f, "unsupported compression type {}", compression),
+            BMPError::UnsupportedBitsPerPixel(bpp) =>
+                write!(f, "unsupported pixel depth of {} bits", bpp),
+            BMPError::BitfieldsNotSupportedForPixelDepth(bpp) =>
+                write!(f, "bitfields are not supported with {} bits per pixel", bpp),
+            BMPError::BitfieldsNotContiguous(red, green, blue, alpha) => {
+                let mut separator = ":";
+
+                write!(f, "bitfield masks are not contiguous")?;
+                for (name, mask) in MASK_NAMES.iter().zip(&[red, green, blue, alpha]) {
+                    if !mask_is_contiguous(*mask) {
+                        write!(f, "{} {} {:#010x}", separator, name, mask)?;
+                        separator = ",";
+                    }
+                }
+                Ok(())
+            },
+            BMPError::BitfieldsOverlap(red, green, blue, alpha) => {
+                let masks = [red, green, blue, alpha];
+                let mut separator = ":";
+
+                write!(f, "bitfield masks overlap")?;
+                for i in 0..masks.len() {
+                    for j in i + 1..masks.len() {
+                        if masks[i] & masks[j] != 0 {
+                            write!(f, "{} {} {:#010x} and {} {:#010x}", separator, MASK_NAMES[i], masks[i], MASK_NAMES[j], masks[j])?;
+                            separator = ",";
+                        }
+                    }
+                }
+                Ok(())
+            },
+            BMPError::InvalidWidth(width) =>
+                write!(f, "invalid width {}", width),
+            BMPError::InvalidHeight(height) =>
+                write!(f, "invalid height {}", height),
+            BMPError::HeaderTooLarge(end, pixel_offset) =>
+                write!(f, "headers and palette end at byte {}, past the start of the pixel data at byte {}", end, pixel_offset),
+            BMPError::CompressionNotSupportedForPixelDepth(compression, bpp) =>
+                write!(f, "{:?} compression is not supported with {} bits per pixel", compression, bpp),
+            BMPError::BufferTooSmall(needed, len) =>
+                write!(f, "buffer of length {} is too small, {} needed", len, needed),
+            BMPError::ImageTooLarge { width, height } =>
+                write!(f, "image of {}x{} pixels is too large to decode", width, height),
+            BMPError::TruncatedPixelData { row, column } =>
+                write!(f, "pixel data ends early, at row {} column {}", row, column),
+            BMPError::InvalidHuffmanCode { row, column } =>
+                write!(f, "invalid Modified Huffman code at row {} column {}", row, column),
+            BMPError::PaletteIndexOutOfRange { index, palette_len } =>
+                write!(f, "palette index {} is out of range for a palette of {} colours", index, palette_len),
+            BMPError::UnsupportedWriterVersion(version) =>
+                write!(f, "writing {:?} headers is not supported", version),
+            BMPError::PaletteTooLarge(len, bpp) =>
+                write!(f, "a palette of {} colours does not fit {} bits per pixel", len, bpp),
+            BMPError::UnsupportedWriterCompression(compression) =>
+                write!(f, "writing {:?} compression is not supported", compression),
+            BMPError::CompressionNotSupportedForTopDown(compression) =>
+                write!(f, "{:?} compression is not supported for top-down images", compression),
+            BMPError::ProfileOverlapsPixelData { offset, size } =>
+                write!(f, "colour profile of {} bytes at byte {} overlaps the pixel data", size, offset),
+            BMPError::PixelDataSizeUnknown(compression) =>
+                write!(f, "size of the {:?} compressed pixel data is not given", compression),
+            BMPError::FileSizeMismatch { declared, actual } =>
+                write!(f, "file size is {} bytes, but the header says {}", actual, declared),
+            BMPError::EmbeddedImageNotSupported(format) =>
+                write!(f, "decoding embedded {:?} images is not supported", format),
+            BMPError::EmbeddedImageSizeMismatch { width, height, header_width, header_height } =>
+                write!(f, "embedded image is {}x{}, but the header says {}x{}", width, height, header_width, header_height),
+            #[cfg(feature = "png")]
+            BMPError::PNGError(ref err) =>
+                write!(f, "PNG error: {}", err),
+            BMPError::IOError(ref err) =>
+                write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl Error for BMPError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            #[cfg(feature = "png")]
+            BMPError::PNGError(ref err) => Some(err),
+            BMPError::IOError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CompressionType {
     RGB,
+    RLE8,
+    RLE4,
     Bitfields,
     AlphaBitfields,
+    /* CCITT Group 3 one-dimensional Modified Huffman, which OS/2 2.x headers
+     * use the value of Bitfields for. */
+    Huffman1D,
+    /* Run-length encoding of 24 bit pixels, only found in OS/2 2.x headers. */
+    RLE24,
+    /* The pixel data is a whole JPEG or PNG image, see BMPHeader::embedded_image(). */
+    JPEG,
+    PNG,
+    /* Pixels, or palette entries, are CMYK rather than RGB. */
+    CMYK,
+    CMYKRLE8,
+    CMYKRLE4,
 }
 
 impl CompressionType {
-    fn from_u32(val: u32) -> Result<CompressionType, BMPError> {
+    fn from_u32(val: u32, version: BMPVersion) -> Result<CompressionType, BMPError> {
         match val {
             0 => Ok(CompressionType::RGB),
+            1 => Ok(CompressionType::RLE8),
+            2 => Ok(CompressionType::RLE4),
+            3 if version == BMPVersion::OS2 => Ok(CompressionType::Huffman1D),
             3 => Ok(CompressionType::Bitfields),
+            4 if version == BMPVersion::OS2 => Ok(CompressionType::RLE24),
+            4 => Ok(CompressionType::JPEG),
+            5 if version != BMPVersion::OS2 => Ok(CompressionType::PNG),
+            11 if version != BMPVersion::OS2 => Ok(CompressionType::CMYK),
+            12 if version != BMPVersion::OS2 => Ok(CompressionType::CMYKRLE8),
+            13 if version != BMPVersion::OS2 => Ok(CompressionType::CMYKRLE4),
             6 => Ok(CompressionType::AlphaBitfields),
             _ => Err(BMPError::UnsupportedCompressionType(val)),
         }
     }
 }
 
-#[derive(Copy,Clone)]
+#[derive(Copy,Clone,Debug,PartialEq)]
 pub enum BMPVersion {
     Two,
     Three,
     Four,
     Five,
+    /* The 52 byte header written by Adobe tools, a v3 header followed by the
+     * red, green and blue masks. */
+    ThreeWithMasks,
+    /* The 56 byte header written by Adobe tools, which also has an alpha mask. */
+    ThreeWithAlphaMask,
+    /* The OS/2 2.x header, which has the fields of a v3 header followed by
+     * 24 bytes of its own, and may be cut short after any of them. A 40 byte
+     * OS/2 2.x header reads the same as a v3 header, and 52 and 56 byte ones
+     * are taken to be Adobe headers. */
+    OS2,
 }
 
 impl BMPVersion {
@@ -61,46 +226,120 @@ impl BMPVersion {
             40 => Ok(BMPVersion::Three),
             108 => Ok(BMPVersion::Four),
             124 => Ok(BMPVersion::Five),
+            52 => Ok(BMPVersion::ThreeWithMasks),
+            56 => Ok(BMPVersion::ThreeWithAlphaMask),
+            16..=64 if val % 2 == 0 => Ok(BMPVersion::OS2),
             _ => Err(BMPError::UnsupportedHeaderSize(val)),
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum EmbeddedFormat {
+    JPEG,
+    PNG,
+}
+
+/* A JPEG or PNG image stored in place of the pixel data, which starts at
+ * offset bytes into the file and is length bytes long. The length is None
+ * when the header leaves it out, and the image then runs to the end of the
+ * file. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct EmbeddedImage {
+    pub format: EmbeddedFormat,
+    pub offset: u64,
+    pub length: Option<u64>,
+}
+
+/* The resolution along one axis of the image, in pixels per metre as it is
+ * stored in the file. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Resolution {
+    pub pixels_per_meter: u32,
+}
+
+impl Resolution {
+    /* Rounds to the nearest whole number of pixels per metre, so 72 DPI
+     * becomes 2835. */
+    pub fn from_dpi(dpi: f64) -> Resolution {
+        Resolution {
+            pixels_per_meter: (dpi / 0.0254).round().clamp(0.0, i32::MAX as f64) as u32,
+        }
+    }
+
+    pub fn dpi(&self) -> f64 {
+        self.pixels_per_meter as f64 * 0.0254
+    }
+}
+
+/* The file and DIB headers. The height is negative for top-down images,
+ * n_colors is the number of palette entries that are actually read, and the
+ * palette starts at palette_offset. */
+#[derive(Clone,Debug)]
 pub struct BMPHeader {
     pub version: BMPVersion,
     pub width: u32,
     pub height: i32,
     pub bpp: u16,
+    pub compression: CompressionType,
     pub n_colors: u32,
     pub red_mask: u32,
     pub green_mask: u32,
     pub blue_mask: u32,
     pub alpha_mask: u32,
+    pub file_size: u64,
     pub pixel_offset: u64,
+    pub palette_offset: u64,
+    pub image_size: u32,
+    pub x_pixels_per_meter: i32,
+    pub y_pixels_per_meter: i32,
+    pub colors_important: u32,
+    /* Only v4 and v5 headers have a colour space. */
+    pub color_space: Option<ColorSpace>,
+    /* Only v5 headers have a rendering intent and a profile, and only linked
+     * and embedded profile colour spaces use the profile. */
+    pub rendering_intent: Option<RenderingIntent>,
+    pub profile: Option<ProfileLocation>,
 }
 
-fn mask_is_contiguous(mask: u32) -> bool {
-    if mask == 0 {
-        return true;
+fn skip<R: Read>(source: &mut R, n_bytes: u64) -> Result<(), io::Error> {
+    if io::copy(&mut source.take(n_bytes), &mut io::sink())? < n_bytes {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
     }
 
-    let mask = mask >> mask.trailing_zeros();
+    Ok(())
+}
 
+fn mask_is_contiguous(mask: u32) -> bool {
     if mask == 0 {
-        true;
+        return true;
     }
 
+    /* Once the trailing zeros are shifted out, a contiguous mask is all ones. */
     let mask = mask >> mask.trailing_zeros();
 
-    if mask == 0 {
-        return true;
+    mask & mask.wrapping_add(1) == 0
+}
+
+/* The bytes taken up by the pixel data, or None for compressed images that do
+ * not give their size. */
+pub fn pixel_data_size(header: &BMPHeader) -> Option<u64> {
+    if header.image_size != 0 {
+        return Some(header.image_size as u64);
     }
 
-    return false;
+    match header.compression {
+        CompressionType::RGB | CompressionType::Bitfields | CompressionType::AlphaBitfields | CompressionType::CMYK => {
+            ((header.width as u64 * header.bpp as u64).div_ceil(32) * 4).checked_mul(header.height.unsigned_abs() as u64)
+        },
+        _ => None,
+    }
 }
 
 impl BMPHeader {
-    fn new(version: BMPVersion, width: i32, height: i32, planes: u16, bpp: u16, n_colors: u32, pixel_offset: u64) -> Result<BMPHeader, BMPError> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(version: BMPVersion, width: i32, height: i32, planes: u16, bpp: u16, compression: CompressionType, n_colors: u32, pixel_offset: u64) -> Result<BMPHeader, BMPError> {
         if width <= 0 {
             return Err(BMPError::InvalidWidth(width));
         }
@@ -113,15 +352,30 @@ impl BMPHeader {
             return Err(BMPError::UnsupportedNumberOfPlanes(planes));
         }
 
+        match (compression, bpp) {
+            (CompressionType::RLE8, 8) | (CompressionType::RLE4, 4) => (),
+            (CompressionType::Huffman1D, 1) | (CompressionType::RLE24, 24) => (),
+            (CompressionType::CMYKRLE8, 8) | (CompressionType::CMYKRLE4, 4) => (),
+            (CompressionType::CMYK, 1) | (CompressionType::CMYK, 2) | (CompressionType::CMYK, 4) |
+            (CompressionType::CMYK, 8) | (CompressionType::CMYK, 32) => (),
+            (CompressionType::RLE8, _) | (CompressionType::RLE4, _) |
+            (CompressionType::Huffman1D, _) | (CompressionType::RLE24, _) |
+            (CompressionType::CMYK, _) | (CompressionType::CMYKRLE8, _) | (CompressionType::CMYKRLE4, _) => return Err(BMPError::CompressionNotSupportedForPixelDepth(compression, bpp)),
+            _ => (),
+        }
+
         Ok(BMPHeader {
             version,
-            width: width.abs() as u32,
+            width: width.unsigned_abs(),
             height,
             bpp,
-            n_colors: if bpp < 16 && (n_colors == 0 || n_colors > 1 << bpp) {
+            compression,
+            n_colors: if bpp >= 16 || compression == CompressionType::JPEG || compression == CompressionType::PNG {
+                0
+            } else if n_colors == 0 || n_colors > 1 << bpp {
                 1 << bpp
             } else {
-                0
+                n_colors
             },
             red_mask: match bpp {
                 16 => BMP_BITFIELD16_RED as u32,
@@ -139,7 +393,54 @@ impl BMPHeader {
                 _ => 0,
             },
             alpha_mask: 0,
+            file_size: 0,
             pixel_offset,
+            palette_offset: 0,
+            image_size: 0,
+            x_pixels_per_meter: 0,
+            y_pixels_per_meter: 0,
+            colors_important: 0,
+            color_space: None,
+            rendering_intent: None,
+            profile: None,
+        })
+    }
+
+    pub fn is_top_down(&self) -> bool {
+        self.height < 0
+    }
+
+    pub fn is_cmyk(&self) -> bool {
+        matches!(self.compression, CompressionType::CMYK | CompressionType::CMYKRLE8 | CompressionType::CMYKRLE4)
+    }
+
+    /* The horizontal and vertical resolution. Either is None when the file
+     * leaves it as zero, which means it is unknown, or when it is negative. */
+    pub fn resolution(&self) -> (Option<Resolution>, Option<Resolution>) {
+        let resolution = |pixels_per_meter: i32| {
+            if pixels_per_meter > 0 {
+                Some(Resolution{pixels_per_meter: pixels_per_meter as u32})
+            } else {
+                None
+            }
+        };
+
+        (resolution(self.x_pixels_per_meter), resolution(self.y_pixels_per_meter))
+    }
+
+    /* Where to find the JPEG or PNG image that makes up the pixel data, if
+     * there is one, so it can be handed to another decoder. */
+    pub fn embedded_image(&self) -> Option<EmbeddedImage> {
+        let format = match self.compression {
+            CompressionType::JPEG => EmbeddedFormat::JPEG,
+            CompressionType::PNG => EmbeddedFormat::PNG,
+            _ => return None,
+        };
+
+        Some(EmbeddedImage {
+            format,
+            offset: self.pixel_offset,
+            length: if self.image_size == 0 { None } else { Some(self.image_size as u64) },
         })
     }
 
@@ -166,96 +467,175 @@ impl BMPHeader {
         Ok(())
     }
 
-    fn from_v2_buffer<R: Read + Seek>(source: &mut R, pixel_offset: u64) -> Result<BMPHeader, BMPError> {
+    fn from_v2_buffer<R: Read>(source: &mut R, pixel_offset: u64) -> Result<BMPHeader, BMPError> {
         let width = source.read_u16::<LittleEndian>()? as i32;
         let height = source.read_u16::<LittleEndian>()? as i32;
         let planes =source.read_u16::<LittleEndian>()?;
         let bpp = source.read_u16::<LittleEndian>()?;
 
-        BMPHeader::new(BMPVersion::Two, width, height, planes, bpp, 0, pixel_offset)
+        BMPHeader::new(BMPVersion::Two, width, height, planes, bpp, CompressionType::RGB, 0, pixel_offset)
     }
 
-    fn from_v3_buffer<R: Read + Seek>(source: &mut R, version: BMPVersion, pixel_offset: u64) -> Result<BMPHeader, BMPError> {
+    fn from_v3_buffer<R: Read>(source: &mut R, version: BMPVersion, pixel_offset: u64) -> Result<BMPHeader, BMPError> {
         let width = source.read_i32::<LittleEndian>()?;
         let height = source.read_i32::<LittleEndian>()?;
         let planes =source.read_u16::<LittleEndian>()?;
         let bpp = source.read_u16::<LittleEndian>()?;
-        let compression = CompressionType::from_u32(source.read_u32::<LittleEndian>()?)?;
-        source.seek(SeekFrom::Current(12))?; /* skip ImageSize, XRes and YRes */
+        let compression = CompressionType::from_u32(source.read_u32::<LittleEndian>()?, version)?;
+        let image_size = source.read_u32::<LittleEndian>()?;
+        let x_pixels_per_meter = source.read_i32::<LittleEndian>()?;
+        let y_pixels_per_meter = source.read_i32::<LittleEndian>()?;
         let n_colors = source.read_u32::<LittleEndian>()?;
-        source.seek(SeekFrom::Current(4))?; /* skip ColorsImportant */
-        let mut header = BMPHeader::new(version, width, height, planes, bpp, n_colors, pixel_offset)?;
+        let colors_important = source.read_u32::<LittleEndian>()?;
+        let mut header = BMPHeader::new(version, width, height, planes, bpp, compression, n_colors, pixel_offset)?;
+
+        header.image_size = image_size;
+        header.x_pixels_per_meter = x_pixels_per_meter;
+        header.y_pixels_per_meter = y_pixels_per_meter;
+        header.colors_important = colors_important;
 
         match version {
             BMPVersion::Two => panic!(),
             BMPVersion::Three => {
                 match compression {
-                    CompressionType::RGB => (),
-                    CompressionType::Bitfields | CompressionType::AlphaBitfields => {
+                    CompressionType::RGB | CompressionType::RLE8 | CompressionType::RLE4 |
+                    CompressionType::Huffman1D | CompressionType::RLE24 | CompressionType::JPEG | CompressionType::PNG |
+                    CompressionType::CMYK | CompressionType::CMYKRLE8 | CompressionType::CMYKRLE4 => (),
+                    CompressionType::Bitfields => {
                         header.set_masks(source.read_u32::<LittleEndian>()?,
                                          source.read_u32::<LittleEndian>()?,
                                          source.read_u32::<LittleEndian>()?,
                                          0)?;
+                        header.palette_offset = 12;
                     },
-                }
-            },
-            BMPVersion::Four => {
-                match compression {
-                    CompressionType::RGB => {
-                        /* We ignore the rest of the v4 header. */
-                        source.seek(SeekFrom::Current(72))?;
-                    },
-                    CompressionType::Bitfields | CompressionType::AlphaBitfields => {
+                    CompressionType::AlphaBitfields => {
                         header.set_masks(source.read_u32::<LittleEndian>()?,
                                          source.read_u32::<LittleEndian>()?,
                                          source.read_u32::<LittleEndian>()?,
                                          source.read_u32::<LittleEndian>()?)?;
-                        /* We ignore the rest of the v4 header. */
-                        source.seek(SeekFrom::Current(56))?;
+                        header.palette_offset = 16;
                     },
                 }
             },
-            BMPVersion::Five => {
+            BMPVersion::ThreeWithMasks | BMPVersion::ThreeWithAlphaMask => {
+                let red_mask = source.read_u32::<LittleEndian>()?;
+                let green_mask = source.read_u32::<LittleEndian>()?;
+                let blue_mask = source.read_u32::<LittleEndian>()?;
+                let alpha_mask = match version {
+                    BMPVersion::ThreeWithAlphaMask => source.read_u32::<LittleEndian>()?,
+                    _ => 0,
+                };
+
+                /* The masks are always there, but only apply to bitfields. */
                 match compression {
-                    CompressionType::RGB => {
-                        /* We ignore the rest of the v5 header. */
-                        source.seek(SeekFrom::Current(84))?;
+                    CompressionType::Bitfields | CompressionType::AlphaBitfields => {
+                        header.set_masks(red_mask, green_mask, blue_mask, alpha_mask)?;
                     },
+                    _ => (),
+                }
+            },
+            BMPVersion::Four | BMPVersion::Five => {
+                let red_mask = source.read_u32::<LittleEndian>()?;
+                let green_mask = source.read_u32::<LittleEndian>()?;
+                let blue_mask = source.read_u32::<LittleEndian>()?;
+                let alpha_mask = source.read_u32::<LittleEndian>()?;
+
+                match compression {
                     CompressionType::Bitfields | CompressionType::AlphaBitfields => {
-                        header.set_masks(source.read_u32::<LittleEndian>()?,
-                                         source.read_u32::<LittleEndian>()?,
-                                         source.read_u32::<LittleEndian>()?,
-                                         source.read_u32::<LittleEndian>()?)?;
-                        /* We ignore the rest of the v4 header. */
-                        source.seek(SeekFrom::Current(68))?;
+                        header.set_masks(red_mask, green_mask, blue_mask, alpha_mask)?;
                     },
+                    _ => (),
+                }
+
+                header.color_space = Some(ColorSpace::read(source)?);
+
+                if version == BMPVersion::Five {
+                    header.rendering_intent = Some(RenderingIntent::from_u32(source.read_u32::<LittleEndian>()?));
+
+                    /* The profile offset counts from the start of the DIB header. */
+                    let profile_offset = source.read_u32::<LittleEndian>()? as u64;
+                    let profile_size = source.read_u32::<LittleEndian>()? as u64;
+                    match header.color_space {
+                        Some(ColorSpace::LinkedProfile) | Some(ColorSpace::EmbeddedProfile) if profile_offset != 0 => {
+                            header.profile = Some(ProfileLocation{offset: 14 + profile_offset, size: profile_size});
+                        },
+                        _ => (),
+                    }
+
+                    /* Reserved. */
+                    skip(source, 4)?;
                 }
             },
+            BMPVersion::OS2 => {
+                /* We ignore the units, halftoning and colour encoding. */
+                skip(source, 24)?;
+            },
         }
 
         Ok(header)
     }
 
-    pub fn from_buffer<R: Read + Seek>(mut source: &mut R) -> Result<BMPHeader, BMPError> {
+    /* Reads the headers from the start of a file, leaving source at the start
+     * of the palette. Only the bytes of the headers are consumed, so source
+     * does not need to support seeking. */
+    pub fn from_buffer<R: Read>(mut source: &mut R) -> Result<BMPHeader, BMPError> {
         let mut bm = [0, 0];
 
-        source.read(&mut bm)?;
+        source.read_exact(&mut bm)?;
         if bm != b"BM"[..] {
             return Err(BMPError::WrongMagicNumbers(bm[0], bm[1]));
         }
 
-        /* Skip the 32bit file size, and 32 reserved bits. */
-        source.seek(SeekFrom::Current(8))?;
+        let file_size = source.read_u32::<LittleEndian>()? as u64;
+
+        /* Skip 32 reserved bits. */
+        skip(source, 4)?;
 
         /* Read the offset to the pixel array. */
-        let pixel_offset = source.read_u16::<LittleEndian>()? as u64;
-        let version = BMPVersion::from_dib_header_size(source.read_u32::<LittleEndian>()?)?;
-        let header = match version {
+        let pixel_offset = source.read_u32::<LittleEndian>()? as u64;
+        let dib_header_size = source.read_u32::<LittleEndian>()?;
+        let version = BMPVersion::from_dib_header_size(dib_header_size)?;
+        let mut header = match version {
             BMPVersion::Two => BMPHeader::from_v2_buffer(&mut source, pixel_offset)?,
-            BMPVersion::Three | BMPVersion::Four | BMPVersion::Five => BMPHeader::from_v3_buffer(&mut source, version, pixel_offset)?,
+            BMPVersion::Three | BMPVersion::ThreeWithMasks | BMPVersion::ThreeWithAlphaMask |
+            BMPVersion::Four | BMPVersion::Five => BMPHeader::from_v3_buffer(&mut source, version, pixel_offset)?,
+            BMPVersion::OS2 => {
+                /* Fields missing from a truncated header are zero. */
+                let mut buffer = [0; 60];
+                source.read_exact(&mut buffer[..dib_header_size as usize - 4])?;
+                BMPHeader::from_v3_buffer(&mut &buffer[..], version, pixel_offset)?
+            },
         };
 
+        /* The masks that follow a v3 header have already been accounted for. */
+        header.palette_offset += 14 + dib_header_size as u64;
+        header.file_size = file_size;
+
         Ok(header)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(BMPError::BitfieldsOverlap(0xff00, 0x0ff0, 0x00ff, 0).to_string(),
+                   "bitfield masks overlap: red 0x0000ff00 and green 0x00000ff0, green 0x00000ff0 and blue 0x000000ff");
+        assert_eq!(BMPError::BitfieldsNotContiguous(0xf0f0, 0x0f00, 0x000f, 0).to_string(),
+                   "bitfield masks are not contiguous: red 0x0000f0f0");
+        assert_eq!(BMPError::HeaderTooLarge(70, 54).to_string(),
+                   "headers and palette end at byte 70, past the start of the pixel data at byte 54");
+    }
+
+    #[test]
+    fn test_error_source() {
+        let err = BMPError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        let boxed: Box<dyn Error> = Box::new(err);
+
+        assert!(boxed.source().is_some());
+        assert!(BMPError::InvalidWidth(0).source().is_none());
+    }
+}
@@ -0,0 +1,57 @@
+use bmp_pixels::Pixel;
+
+/* What to produce for pixels that the image data never sets, which happens
+ * when an RLE bitmap moves the cursor with a delta escape or ends early. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum UndefinedPixels {
+    /* Fully transparent black, as web browsers render them. */
+    Transparent,
+    /* The first entry of the colour palette. */
+    FirstPaletteEntry,
+    Color(Pixel),
+}
+
+/* What to produce for palette indices that are past the end of the palette,
+ * as found in malformed files that declare a short palette. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum InvalidPaletteIndex {
+    /* Fail with BMPError::PaletteIndexOutOfRange. */
+    Error,
+    /* Use a fixed colour instead, web browsers use opaque black. */
+    Color(Pixel),
+}
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct BMPOptions {
+    pub undefined_pixels: UndefinedPixels,
+    pub invalid_palette_index: InvalidPaletteIndex,
+    /* Images with 64 bits per pixel store linear light, which is converted to
+     * sRGB unless this is set. */
+    pub linear_64bpp: bool,
+    /* CMYK images are converted to RGB unless this is set, in which case
+     * cyan, magenta, yellow and black are returned in the red, green, blue
+     * and alpha channels. */
+    pub raw_cmyk: bool,
+    /* Convert images whose header gives a calibrated RGB colour space to
+     * sRGB, using their endpoints and gammas. Otherwise the RGB values are
+     * returned as they are. Pixels read with raw_cmyk or linear_64bpp, and
+     * embedded images, are never converted. */
+    pub convert_calibrated_rgb: bool,
+    /* Files that are shorter than the file size in their header, and too short
+     * for their pixel data, fail with BMPError::FileSizeMismatch unless this is
+     * set. A file size of zero means it is unknown and is never checked. */
+    pub lenient_file_size: bool,
+}
+
+impl Default for BMPOptions {
+    fn default() -> BMPOptions {
+        BMPOptions {
+            undefined_pixels: UndefinedPixels::Transparent,
+            invalid_palette_index: InvalidPaletteIndex::Error,
+            linear_64bpp: false,
+            raw_cmyk: false,
+            convert_calibrated_rgb: false,
+            lenient_file_size: false,
+        }
+    }
+}
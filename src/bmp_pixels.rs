@@ -1,8 +1,22 @@
 use bitreader::BitReader;
-use bmp_header::{BMPHeader,BMPError,BMPVersion};
-use byteorder::{LittleEndian,ReadBytesExt};
+use bmp_options::{BMPOptions,InvalidPaletteIndex,UndefinedPixels};
+use bmp_header::{BMPHeader,BMPError,BMPVersion,CompressionType,pixel_data_size};
+#[cfg(feature = "png")]
+use bmp_header::EmbeddedFormat;
+use byteorder::{ByteOrder,LittleEndian};
+use huffman::HuffmanDecoder;
+#[cfg(feature = "png")]
+use png;
+use rle::{RLEDecoder,RLEMode};
 use std::io::{self,Read,Seek,SeekFrom};
+use std::mem;
 
+/* Row buffers are allocated from the declared width before any pixel data is
+ * read, so widths that would need more than this for a row of Pixels, or for
+ * a row of pixel data, are refused rather than trusted. */
+const MAX_ROW_BYTES: usize = 1 << 28;
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub struct Pixel {
     pub red: u32,
     pub green: u32,
@@ -10,41 +24,79 @@ pub struct Pixel {
     pub alpha: u32,
 }
 
+/* The layout of decoded pixels in a byte buffer, with 8 bits per channel. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum PixelFormat {
+    RGBA8,
+    RGB8,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::RGBA8 => 4,
+            PixelFormat::RGB8 => 3,
+        }
+    }
+}
+
 fn upscale(from: u32, bits: u8) -> u32 {
     let mut to = from;
 
     for _ in 1..(32/bits) {
-        to = to << bits | from;
+        to = (to << bits) | from;
     }
 
     if 32 % bits != 0 {
-        to = to << 32 % bits | from >> (32 - 32 % bits);
+        to = (to << (32 % bits)) | (from >> (bits - 32 % bits));
     }
 
     to
 }
 
 fn mask(px: u32, mask: u32) -> u32 {
-    return upscale((px & mask) >> mask.trailing_zeros(), !(mask >> mask.trailing_zeros()).trailing_zeros() as u8);
+    upscale((px & mask) >> mask.trailing_zeros(), (mask >> mask.trailing_zeros()).count_ones() as u8)
 }
 
 fn mask_or_zeros(px: u32, msk: u32) -> u32 {
     if msk == 0 {
-        return 0;
+        0
     } else {
-        return mask(px, msk);
+        mask(px, msk)
     }
 }
 
 fn mask_or_ones(px: u32, msk: u32) -> u32 {
     if msk == 0 {
-        return !0u32;
+        !0u32
+    } else {
+        mask(px, msk)
+    }
+}
+
+/* Scales a channel from 0.0 to 1.0 to the full range of a u32. */
+pub fn from_unit(value: f64) -> u32 {
+    (value.clamp(0.0, 1.0) * u32::MAX as f64).round() as u32
+}
+
+pub fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308 {
+        value * 12.92
     } else {
-        return mask(px, msk);
+        1.055 * value.powf(1.0 / 2.4) - 0.055
     }
 }
 
 impl Pixel {
+    fn transparent() -> Pixel {
+        Pixel {
+            red: 0,
+            green: 0,
+            blue: 0,
+            alpha: 0,
+        }
+    }
+
     fn from_pallete_pixel(px: &PalletePixel) -> Pixel {
         Pixel{
             red: upscale(px.red as u32, 8),
@@ -62,135 +114,457 @@ impl Pixel {
             alpha: mask_or_ones(px, alpha),
         }
     }
+
+    /* With raw set, cyan, magenta, yellow and black go in the red, green,
+     * blue and alpha channels instead of being converted to RGB. */
+    fn from_cmyk(bytes: &[u8], raw: bool) -> Pixel {
+        if raw {
+            Pixel {
+                red: upscale(bytes[3] as u32, 8),
+                green: upscale(bytes[2] as u32, 8),
+                blue: upscale(bytes[1] as u32, 8),
+                alpha: upscale(bytes[0] as u32, 8),
+            }
+        } else {
+            Pixel::from_pallete_pixel(&cmyk_to_rgb(bytes))
+        }
+    }
+
+    /* Converts BGRA channels in s2.13 fixed point, where 8192 is 1.0. */
+    fn from_fixed_point(bytes: &[u8], linear: bool) -> Pixel {
+        let channel = |i: usize| LittleEndian::read_i16(&bytes[2 * i..]) as f64 / 8192.0;
+        let color = |i: usize| if linear {
+            from_unit(channel(i))
+        } else {
+            from_unit(linear_to_srgb(channel(i).clamp(0.0, 1.0)))
+        };
+
+        Pixel {
+            red: color(2),
+            green: color(1),
+            blue: color(0),
+            alpha: from_unit(channel(3)),
+        }
+    }
+
+    pub fn write_bytes(&self, bytes: &mut [u8], format: PixelFormat) {
+        bytes[0] = (self.red >> 24) as u8;
+        bytes[1] = (self.green >> 24) as u8;
+        bytes[2] = (self.blue >> 24) as u8;
+
+        if format == PixelFormat::RGBA8 {
+            bytes[3] = (self.alpha >> 24) as u8;
+        }
+    }
 }
 
+/* A palette entry. The name predates the "palette" spelling used by the rest
+ * of the API and is kept for compatibility. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub struct PalletePixel {
-    red: u8,
-    green: u8,
-    blue: u8,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+/* Converts a CMYK value, stored as black, yellow, magenta and cyan bytes, to
+ * RGB without any colour management: each of red, green and blue is the
+ * product of the complements of black and of cyan, magenta or yellow. */
+fn cmyk_to_rgb(bytes: &[u8]) -> PalletePixel {
+    let black = 255 - bytes[0] as u32;
+    let channel = |value: u8| (((255 - value as u32) * black + 127) / 255) as u8;
+
+    PalletePixel {
+        red: channel(bytes[3]),
+        green: channel(bytes[2]),
+        blue: channel(bytes[1]),
+    }
+}
+
+/* Reads the raw palette entries, which are BGR colours with one byte of
+ * padding, or CMYK colours in CMYK images. */
+fn read_pallete_entries<R: Read>(source: &mut R, header: &BMPHeader) -> Result<Vec<[u8; 4]>, io::Error> {
+    let mut pallete = Vec::with_capacity(header.n_colors as usize);
+    let n_bytes = match header.version {
+        BMPVersion::Two => 3,
+        _ => 4,
+    };
+
+    for _ in 0..header.n_colors {
+        let mut px = [0; 4];
+        source.read_exact(&mut px[..n_bytes])?;
+        pallete.push(px);
+    }
+
+    Ok(pallete)
+}
+
+/* Reads the palette that follows the headers. The entries of CMYK images are
+ * converted to RGB as CMYK pixels are. */
+pub fn read_pallete<R: Read>(source: &mut R, header: &BMPHeader) -> Result<Vec<PalletePixel>, io::Error> {
+    Ok(read_pallete_entries(source, header)?.iter().map(|px| {
+        if header.is_cmyk() {
+            cmyk_to_rgb(px)
+        } else {
+            PalletePixel{red: px[2], green: px[1], blue: px[0]}
+        }
+    }).collect())
+}
+
+/* Reads the pixel array one scanline at a time, including the padding that
+ * aligns each scanline to four bytes. */
+pub struct RowReader<'a, R: Read + 'a> {
+    source: &'a mut R,
+    bpp: u16,
+    n_pixel_bytes: usize,
+    row: Vec<u8>,
+}
+
+impl<'a, R: Read + 'a> RowReader<'a, R> {
+    fn new(source: &'a mut R, width: u32, bpp: u16) -> RowReader<'a, R> {
+        let n_bits = width as usize * bpp as usize;
+
+        RowReader {
+            source,
+            bpp,
+            n_pixel_bytes: n_bits.div_ceil(8),
+            row: vec![0; n_bits.div_ceil(32) * 4],
+        }
+    }
+
+    /* Reads the scanline with the given y coordinate. A row that is only
+     * missing its padding, as the last row of some files is, is accepted. */
+    fn read_row(&mut self, y: usize) -> Result<&[u8], BMPError> {
+        let mut n_read = 0;
+
+        while n_read < self.row.len() {
+            match self.source.read(&mut self.row[n_read..]) {
+                Ok(0) => break,
+                Ok(n) => n_read += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(BMPError::IOError(err)),
+            }
+        }
+
+        if n_read < self.n_pixel_bytes {
+            return Err(BMPError::TruncatedPixelData{row: y, column: n_read * 8 / self.bpp as usize});
+        }
+
+        Ok(&self.row)
+    }
+}
+
+/* The palette of an indexed image, along with the colour to use for indices
+ * past its end, if they are not an error. */
+pub struct Pallete {
+    entries: Vec<Pixel>,
+    invalid_index: Option<Pixel>,
+}
+
+impl Pallete {
+    fn get(&self, index: usize) -> Result<Pixel, BMPError> {
+        match (self.entries.get(index), self.invalid_index) {
+            (Some(&entry), _) => Ok(entry),
+            (None, Some(px)) => Ok(px),
+            (None, None) => Err(BMPError::PaletteIndexOutOfRange{index, palette_len: self.entries.len()}),
+        }
+    }
+}
+
+fn pallete_row(pallete: &Pallete, bytes: &[u8], n_bits: u8, row: &mut [Pixel]) -> Result<(), BMPError> {
+    let mut reader = BitReader::new(bytes, n_bits);
+
+    for px in row.iter_mut() {
+        *px = pallete.get(reader.read_bits() as usize)?;
+    }
+
+    Ok(())
 }
 
 pub enum Pixels<'a, R: Read + Seek + 'a> {
-    OneBPP(Vec<PalletePixel>, BitReader<'a, R>),
-    TwoBPP(Vec<PalletePixel>, BitReader<'a, R>),
-    FourBPP(Vec<PalletePixel>, BitReader<'a, R>),
-    EightBPP(Vec<PalletePixel>, &'a mut R),
-    SixteenBPP(u16, u16, u16, u16, &'a mut R),
-    TwentyFourBPP(&'a mut R),
-    ThirtyTwoBPP(u32, u32, u32, u32, &'a mut R),
+    OneBPP(Pallete, RowReader<'a, R>),
+    Huffman1D(Pallete, HuffmanDecoder<'a, R>),
+    TwoBPP(Pallete, RowReader<'a, R>),
+    FourBPP(Pallete, RowReader<'a, R>),
+    RLE4(Pallete, Pixel, RLEDecoder<'a, R>),
+    EightBPP(Pallete, RowReader<'a, R>),
+    RLE8(Pallete, Pixel, RLEDecoder<'a, R>),
+    SixteenBPP(u16, u16, u16, u16, RowReader<'a, R>),
+    TwentyFourBPP(RowReader<'a, R>),
+    RLE24(Pixel, RLEDecoder<'a, R>),
+    ThirtyTwoBPP(u32, u32, u32, u32, RowReader<'a, R>),
+    /* Whether to keep linear light, and the reader. */
+    SixtyFourBPP(bool, RowReader<'a, R>),
+    /* Whether to keep the raw CMYK values, and the reader. */
+    CMYKThirtyTwoBPP(bool, RowReader<'a, R>),
+    /* A decoded embedded image, as its rows in file order and the number of
+     * rows read so far. */
+    #[cfg(feature = "png")]
+    Embedded(Vec<Vec<Pixel>>, usize),
+}
+
+/* Decodes the PNG image that makes up the pixel data. */
+#[cfg(feature = "png")]
+fn decode_png<R: Read>(header: &BMPHeader, source: &mut R) -> Result<Vec<Vec<Pixel>>, BMPError> {
+    let length = header.embedded_image().and_then(|image| image.length).unwrap_or(u64::MAX);
+    let mut decoder = png::Decoder::new(source.take(length));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+
+    let header_height = header.height.unsigned_abs();
+    if info.width != header.width || info.height != header_height {
+        return Err(BMPError::EmbeddedImageSizeMismatch{width: info.width,
+                                                      height: info.height,
+                                                      header_width: header.width,
+                                                      header_height});
+    }
+
+    let n_channels = info.color_type.samples();
+    let mut rows: Vec<Vec<Pixel>> = buffer.chunks(info.line_size).take(info.height as usize).map(|line| {
+        line.chunks_exact(n_channels).take(info.width as usize).map(|bytes| {
+            let (gray_or_red, green, blue) = match n_channels {
+                1 | 2 => (bytes[0], bytes[0], bytes[0]),
+                _ => (bytes[0], bytes[1], bytes[2]),
+            };
+            let alpha = match n_channels {
+                2 => Some(bytes[1]),
+                4 => Some(bytes[3]),
+                _ => None,
+            };
+
+            Pixel {
+                red: upscale(gray_or_red as u32, 8),
+                green: upscale(green as u32, 8),
+                blue: upscale(blue as u32, 8),
+                alpha: alpha.map_or(!0, |alpha| upscale(alpha as u32, 8)),
+            }
+        }).collect()
+    }).collect();
+
+    /* PNG rows are top-down, so put them in the order the header promises. */
+    if !header.is_top_down() {
+        rows.reverse();
+    }
+
+    Ok(rows)
 }
 
 impl<'a, R: Read + Seek + 'a> Pixels<'a, R> {
     fn from_header(header: &BMPHeader,
-                   pallete: Vec<PalletePixel>,
+                   pallete: Vec<Pixel>,
+                   options: &BMPOptions,
                    source: &'a mut R) -> Result<Pixels<'a, R>, BMPError> {
+        let undefined = match options.undefined_pixels {
+            UndefinedPixels::FirstPaletteEntry if !pallete.is_empty() => pallete[0],
+            UndefinedPixels::Color(px) => px,
+            _ => Pixel::transparent(),
+        };
+        let pallete = Pallete {
+            entries: pallete,
+            invalid_index: match options.invalid_palette_index {
+                InvalidPaletteIndex::Error => None,
+                InvalidPaletteIndex::Color(px) => Some(px),
+            },
+        };
+
+        if let Some(image) = header.embedded_image() {
+            return match image.format {
+                #[cfg(feature = "png")]
+                EmbeddedFormat::PNG => Ok(Pixels::Embedded(decode_png(header, source)?, 0)),
+                format => Err(BMPError::EmbeddedImageNotSupported(format)),
+            };
+        }
+
+        match header.compression {
+            CompressionType::CMYK if header.bpp == 32 => return Ok(Pixels::CMYKThirtyTwoBPP(options.raw_cmyk, RowReader::new(source, header.width, header.bpp))),
+            CompressionType::RLE4 | CompressionType::CMYKRLE4 => return Ok(Pixels::RLE4(pallete, undefined, RLEDecoder::new(source, RLEMode::Four, header.width as usize))),
+            CompressionType::RLE8 | CompressionType::CMYKRLE8 => return Ok(Pixels::RLE8(pallete, undefined, RLEDecoder::new(source, RLEMode::Eight, header.width as usize))),
+            CompressionType::RLE24 => return Ok(Pixels::RLE24(undefined, RLEDecoder::new(source, RLEMode::TwentyFour, header.width as usize))),
+            CompressionType::Huffman1D => return Ok(Pixels::Huffman1D(pallete, HuffmanDecoder::new(source, header.width as usize))),
+            _ => (),
+        }
+
         match header.bpp {
-            1 => Ok(Pixels::OneBPP(pallete, BitReader::new(source, 1))),
-            2 => Ok(Pixels::TwoBPP(pallete, BitReader::new(source, 2))),
-            4 => Ok(Pixels::FourBPP(pallete, BitReader::new(source, 4))),
-            8 => Ok(Pixels::EightBPP(pallete, source)),
+            1 | 2 | 4 | 8 | 16 | 24 | 32 | 64 => (),
+            _ => return Err(BMPError::UnsupportedBitsPerPixel(header.bpp)),
+        }
+
+        let reader = RowReader::new(source, header.width, header.bpp);
+
+        match header.bpp {
+            1 => Ok(Pixels::OneBPP(pallete, reader)),
+            2 => Ok(Pixels::TwoBPP(pallete, reader)),
+            4 => Ok(Pixels::FourBPP(pallete, reader)),
+            8 => Ok(Pixels::EightBPP(pallete, reader)),
             16 => Ok(Pixels::SixteenBPP(
                                         header.red_mask as u16,
                                         header.green_mask as u16,
                                         header.blue_mask as u16,
                                         header.alpha_mask as u16,
-                                        source)),
-            24 => Ok(Pixels::TwentyFourBPP(source)),
+                                        reader)),
+            24 => Ok(Pixels::TwentyFourBPP(reader)),
             32 => Ok(Pixels::ThirtyTwoBPP(
                                         header.red_mask,
                                         header.green_mask,
                                         header.blue_mask,
                                         header.alpha_mask,
-                                        source)),
-            _ => Err(BMPError::UnsupportedBitsPerPixel(header.bpp)),
+                                        reader)),
+            64 => Ok(Pixels::SixtyFourBPP(options.linear_64bpp, reader)),
+            _ => unreachable!(),
         }
     }
 
-    pub fn new(mut source: &'a mut R) -> Result<(Pixels<'a, R>, u32, i32), BMPError> {
-        let header = BMPHeader::from_buffer(&mut source)?;
-        let mut pallete = Vec::with_capacity(header.n_colors as usize);
-
-        match header.version {
-            BMPVersion::Two => {
-                for _ in 0..header.n_colors {
-                    let mut px = [0; 3];
-                    source.read(&mut px)?;
-                    pallete.push(PalletePixel{red: px[2], green: px[1], blue: px[0]});
-                }
-            },
-            _ => {
-                for _ in 0..header.n_colors {
-                    let mut px = [0; 4];
-                    source.read(&mut px)?;
-                    pallete.push(PalletePixel{red: px[2], green: px[1], blue: px[0]});
-                }
-            },
+    pub fn new(source: &'a mut R, options: &BMPOptions) -> Result<(Pixels<'a, R>, BMPHeader), BMPError> {
+        let header = BMPHeader::from_buffer(source)?;
+        let width = header.width as u64;
+        let row_bytes = (width * mem::size_of::<Pixel>() as u64).max((width * header.bpp as u64).div_ceil(8));
+        if row_bytes > MAX_ROW_BYTES as u64 {
+            return Err(BMPError::ImageTooLarge{width: header.width, height: header.height.unsigned_abs()});
         }
 
-        let current_offset = source.seek(SeekFrom::Current(0))?;
+        let pallete = read_pallete_entries(source, &header)?.iter().map(|px| {
+            if header.is_cmyk() {
+                Pixel::from_cmyk(px, options.raw_cmyk)
+            } else {
+                Pixel::from_pallete_pixel(&PalletePixel{red: px[2], green: px[1], blue: px[0]})
+            }
+        }).collect();
+
+        let current_offset = source.stream_position()?;
         if current_offset > header.pixel_offset {
             return Err(BMPError::HeaderTooLarge(current_offset, header.pixel_offset));
         }
-        source.seek(SeekFrom::Start(header.pixel_offset))?;
-
-        Ok((Pixels::from_header(&header, pallete, source)?, header.width, header.height))
-    }
 
-    pub fn seek_to_byte_boundary(&mut self, align: u64) -> Result<(), io::Error> {
-        match self {
-            &mut Pixels::OneBPP(_, ref mut reader) |
-            &mut Pixels::TwoBPP(_, ref mut reader) |
-            &mut Pixels::FourBPP(_, ref mut reader) => {
-                return reader.seek_to_byte_boundary(align);
-            },
-            &mut Pixels::EightBPP(_, ref mut reader) |
-            &mut Pixels::SixteenBPP(_, _, _, _, ref mut reader) |
-            &mut Pixels::TwentyFourBPP(ref mut reader) |
-            &mut Pixels::ThirtyTwoBPP(_, _, _, _, ref mut reader) => {
-                let position = reader.seek(SeekFrom::Current(0))?;
-
-                if position % align != 0 {
-                    reader.seek(SeekFrom::Current((align - (position % align)) as i64))?;
-                }
+        /* Many encoders write a file size of zero, or one that is off, so only
+         * a file that is too short for both its declared size and its pixel
+         * data is rejected. */
+        if !options.lenient_file_size && header.file_size != 0 {
+            let file_size = source.seek(SeekFrom::End(0))?;
+            let truncated = pixel_data_size(&header).is_some_and(|size| file_size < header.pixel_offset.saturating_add(size));
 
-                return Ok(());
+            if file_size < header.file_size && truncated {
+                return Err(BMPError::FileSizeMismatch{declared: header.file_size, actual: file_size});
             }
         }
+        source.seek(SeekFrom::Start(header.pixel_offset))?;
+
+        Ok((Pixels::from_header(&header, pallete, options, source)?, header))
     }
 
-    pub fn next_pixel(&mut self) -> Result<Pixel, io::Error> {
+    /* Decodes the next scanline in file order into row, which holds exactly
+     * one pixel per column. The y coordinate is only used to report errors. */
+    pub fn read_row(&mut self, y: usize, row: &mut [Pixel]) -> Result<(), BMPError> {
         match self {
-            &mut Pixels::OneBPP(ref pallete, ref mut reader) |
-            &mut Pixels::TwoBPP(ref pallete, ref mut reader) |
+            &mut Pixels::OneBPP(ref pallete, ref mut reader) => {
+                pallete_row(pallete, reader.read_row(y)?, 1, row)?;
+            },
+            &mut Pixels::Huffman1D(ref pallete, ref mut decoder) => {
+                let indices = match decoder.read_row() {
+                    Ok(indices) => indices,
+                    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(BMPError::TruncatedPixelData{row: y, column: decoder.column()});
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                        return Err(BMPError::InvalidHuffmanCode{row: y, column: decoder.column()});
+                    },
+                    Err(err) => return Err(BMPError::IOError(err)),
+                };
+
+                for (px, &index) in row.iter_mut().zip(indices) {
+                    *px = pallete.get(index as usize)?;
+                }
+            },
+            &mut Pixels::TwoBPP(ref pallete, ref mut reader) => {
+                pallete_row(pallete, reader.read_row(y)?, 2, row)?;
+            },
             &mut Pixels::FourBPP(ref pallete, ref mut reader) => {
-                Ok(Pixel::from_pallete_pixel(&pallete[reader.read_bits()? as usize]))
+                pallete_row(pallete, reader.read_row(y)?, 4, row)?;
             },
             &mut Pixels::EightBPP(ref pallete, ref mut reader) => {
-                Ok(Pixel::from_pallete_pixel(&pallete[reader.read_u8()? as usize]))
+                pallete_row(pallete, reader.read_row(y)?, 8, row)?;
+            },
+            &mut Pixels::RLE4(ref pallete, undefined, ref mut decoder) |
+            &mut Pixels::RLE8(ref pallete, undefined, ref mut decoder) => {
+                let indices = match decoder.read_row() {
+                    Ok(indices) => indices,
+                    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(BMPError::TruncatedPixelData{row: y, column: decoder.column()});
+                    },
+                    Err(err) => return Err(BMPError::IOError(err)),
+                };
+
+                for (px, index) in row.iter_mut().zip(indices) {
+                    *px = match *index {
+                        Some(index) => pallete.get(index as usize)?,
+                        None => undefined,
+                    };
+                }
             },
             &mut Pixels::SixteenBPP(red_mask, green_mask, blue_mask, alpha_mask, ref mut reader) => {
-                return Ok(Pixel::from_bitfields(reader.read_u16::<LittleEndian>()? as u32,
+                for (px, bytes) in row.iter_mut().zip(reader.read_row(y)?.chunks_exact(2)) {
+                    *px = Pixel::from_bitfields(LittleEndian::read_u16(bytes) as u32,
                                                 red_mask as u32,
                                                 green_mask as u32,
                                                 blue_mask as u32,
-                                                alpha_mask as u32))
+                                                alpha_mask as u32);
+                }
             },
             &mut Pixels::TwentyFourBPP(ref mut reader) => {
-                let mut px = [0; 3];
-                reader.read(&mut px)?;
+                for (px, bytes) in row.iter_mut().zip(reader.read_row(y)?.chunks_exact(3)) {
+                    *px = Pixel::from_pallete_pixel(&PalletePixel{red: bytes[2],
+                                                                  green: bytes[1],
+                                                                  blue: bytes[0]});
+                }
+            },
+            &mut Pixels::RLE24(undefined, ref mut decoder) => {
+                let values = match decoder.read_row() {
+                    Ok(values) => values,
+                    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(BMPError::TruncatedPixelData{row: y, column: decoder.column()});
+                    },
+                    Err(err) => return Err(BMPError::IOError(err)),
+                };
 
-                return Ok(Pixel::from_pallete_pixel(&PalletePixel{red: px[0],
-                                                                 green: px[1],
-                                                                 blue: px[2]}))
+                for (px, value) in row.iter_mut().zip(values) {
+                    *px = match *value {
+                        Some(value) => Pixel::from_pallete_pixel(&PalletePixel{red: (value >> 16) as u8,
+                                                                              green: (value >> 8) as u8,
+                                                                              blue: value as u8}),
+                        None => undefined,
+                    };
+                }
             },
             &mut Pixels::ThirtyTwoBPP(red_mask, green_mask, blue_mask, alpha_mask, ref mut reader) => {
-                return Ok(Pixel::from_bitfields(reader.read_u32::<LittleEndian>()?,
+                for (px, bytes) in row.iter_mut().zip(reader.read_row(y)?.chunks_exact(4)) {
+                    *px = Pixel::from_bitfields(LittleEndian::read_u32(bytes),
                                                 red_mask,
                                                 green_mask,
                                                 blue_mask,
-                                                alpha_mask))
+                                                alpha_mask);
+                }
+            },
+            &mut Pixels::CMYKThirtyTwoBPP(raw, ref mut reader) => {
+                for (px, bytes) in row.iter_mut().zip(reader.read_row(y)?.chunks_exact(4)) {
+                    *px = Pixel::from_cmyk(bytes, raw);
+                }
+            },
+            &mut Pixels::SixtyFourBPP(linear, ref mut reader) => {
+                for (px, bytes) in row.iter_mut().zip(reader.read_row(y)?.chunks_exact(8)) {
+                    *px = Pixel::from_fixed_point(bytes, linear);
+                }
+            },
+            #[cfg(feature = "png")]
+            &mut Pixels::Embedded(ref rows, ref mut n_rows_read) => {
+                if let Some(pixels) = rows.get(*n_rows_read) {
+                    row.copy_from_slice(pixels);
+                }
+                *n_rows_read += 1;
             },
         }
+
+        Ok(())
     }
 }
-
@@ -0,0 +1,538 @@
+use bmp_header::{BMPError,BMPVersion,CompressionType,Resolution};
+use bmp_pixels::{Pixel,PalletePixel};
+use byteorder::{LittleEndian,WriteBytesExt};
+use quantizer;
+use rle::{self,RLEMode};
+use std::io::Write;
+
+const BMP_BITFIELD16_RED: u32   = 0b1111100000000000;
+const BMP_BITFIELD16_GREEN: u32 = 0b0000011111100000;
+const BMP_BITFIELD16_BLUE: u32  = 0b0000000000011111;
+const BMP_BITFIELD32_RED: u32   = 0x00ff0000;
+const BMP_BITFIELD32_GREEN: u32 = 0x0000ff00;
+const BMP_BITFIELD32_BLUE: u32  = 0x000000ff;
+const BMP_BITFIELD32_ALPHA: u32 = 0xff000000;
+
+const BI_RGB: u32 = 0;
+const BI_RLE8: u32 = 1;
+const BI_RLE4: u32 = 2;
+const BI_BITFIELDS: u32 = 3;
+
+const LCS_SRGB: u32 = 0x73524742;
+const LCS_GM_IMAGES: u32 = 4;
+
+/* Writes images as BMP files.
+ *
+ * Images with 1, 4 or 8 bits per pixel are written with a palette, either the
+ * one given to write_indexed(), or the one set with set_palette() that the
+ * pixels given to write() are matched against. Without set_palette(), write()
+ * generates a palette from the pixels, see quantize(). Images with 16 bits per pixel
+ * use 5-6-5 bitfields, and images with 32 bits per pixel keep their alpha
+ * channel, except in v3 files where there is no room for an alpha mask.
+ *
+ * Images with 4 or 8 bits per pixel can also be run-length encoded, see
+ * set_compression().
+ *
+ * Pixels are given top row first, and are written bottom-up unless
+ * set_top_down() is used. */
+pub struct BMPWriter {
+    version: BMPVersion,
+    bpp: u16,
+    compression: CompressionType,
+    top_down: bool,
+    dither: bool,
+    x_resolution: Option<Resolution>,
+    y_resolution: Option<Resolution>,
+    pallete: Vec<PalletePixel>,
+}
+
+impl BMPWriter {
+    pub fn new(version: BMPVersion, bpp: u16) -> Result<BMPWriter, BMPError> {
+        match version {
+            BMPVersion::Three | BMPVersion::Four | BMPVersion::Five => (),
+            _ => return Err(BMPError::UnsupportedWriterVersion(version)),
+        }
+
+        match bpp {
+            1 | 4 | 8 | 16 | 24 | 32 => (),
+            _ => return Err(BMPError::UnsupportedBitsPerPixel(bpp)),
+        }
+
+        Ok(BMPWriter {
+            version,
+            bpp,
+            compression: CompressionType::RGB,
+            top_down: false,
+            dither: false,
+            x_resolution: None,
+            y_resolution: None,
+            pallete: Vec::new(),
+        })
+    }
+
+    /* Only RLE4 with 4 bits per pixel and RLE8 with 8 bits per pixel can be
+     * chosen, other images are always written uncompressed or with bitfields.
+     * Compressed images cannot be top-down. */
+    pub fn set_compression(&mut self, compression: CompressionType) -> Result<(), BMPError> {
+        match (compression, self.bpp) {
+            (CompressionType::RGB, _) | (CompressionType::RLE4, 4) | (CompressionType::RLE8, 8) => (),
+            (CompressionType::RLE4, _) | (CompressionType::RLE8, _) => {
+                return Err(BMPError::CompressionNotSupportedForPixelDepth(compression, self.bpp));
+            },
+            _ => return Err(BMPError::UnsupportedWriterCompression(compression)),
+        }
+
+        self.compression = compression;
+        Ok(())
+    }
+
+    pub fn set_top_down(&mut self, top_down: bool) {
+        self.top_down = top_down;
+    }
+
+    /* Whether write() dithers pixels when matching them against a palette. */
+    pub fn set_dithering(&mut self, dither: bool) {
+        self.dither = dither;
+    }
+
+    /* The horizontal and vertical resolution, written as zero, meaning
+     * unknown, when None, which is the default. */
+    pub fn set_resolution(&mut self, x_resolution: Option<Resolution>, y_resolution: Option<Resolution>) {
+        self.x_resolution = x_resolution;
+        self.y_resolution = y_resolution;
+    }
+
+    pub fn set_palette(&mut self, pallete: Vec<PalletePixel>) -> Result<(), BMPError> {
+        if self.bpp > 8 || pallete.len() > 1 << self.bpp {
+            return Err(BMPError::PaletteTooLarge(pallete.len(), self.bpp));
+        }
+
+        self.pallete = pallete;
+        Ok(())
+    }
+
+    /* Writes width * height pixels, top row first. */
+    pub fn write<W: Write>(&self, dest: &mut W, width: u32, height: u32, pixels: &[Pixel]) -> Result<(), BMPError> {
+        let n_pixels = width as usize * height as usize;
+
+        if pixels.len() < n_pixels {
+            return Err(BMPError::BufferTooSmall(n_pixels, pixels.len()));
+        }
+
+        if self.bpp <= 8 {
+            let pixels = &pixels[..n_pixels];
+
+            if self.pallete.is_empty() {
+                let (pallete, indices) = quantizer::quantize(pixels, width as usize, 1 << self.bpp, self.dither);
+                return self.write_indexed(dest, width, height, &pallete, &indices);
+            }
+
+            let indices = quantizer::map_to_palette(pixels, width as usize, &self.pallete, self.dither)?;
+            return self.write_indexed(dest, width, height, &self.pallete, &indices);
+        }
+
+        self.write_header(dest, width, height, &[], self.row_size(width) as u64 * height as u64)?;
+
+        let mut row = vec![0; self.row_size(width)];
+        for y in 0..height as usize {
+            let y = if self.top_down { y } else { height as usize - 1 - y };
+
+            self.encode_row(&pixels[y * width as usize..(y + 1) * width as usize], &mut row);
+            dest.write_all(&row)?;
+        }
+
+        Ok(())
+    }
+
+    /* Like write(), but takes the pixels from an iterator. */
+    pub fn write_iter<W: Write, I: IntoIterator<Item=Pixel>>(&self, dest: &mut W, width: u32, height: u32, pixels: I) -> Result<(), BMPError> {
+        let pixels: Vec<Pixel> = pixels.into_iter().take(width as usize * height as usize).collect();
+
+        self.write(dest, width, height, &pixels)
+    }
+
+    /* Writes an image with the given palette and one palette index per pixel,
+     * top row first. */
+    pub fn write_indexed<W: Write>(&self, dest: &mut W, width: u32, height: u32, pallete: &[PalletePixel], indices: &[u8]) -> Result<(), BMPError> {
+        let n_pixels = width as usize * height as usize;
+
+        if self.bpp > 8 || pallete.len() > 1 << self.bpp {
+            return Err(BMPError::PaletteTooLarge(pallete.len(), self.bpp));
+        }
+
+        if indices.len() < n_pixels {
+            return Err(BMPError::BufferTooSmall(n_pixels, indices.len()));
+        }
+
+        if let Some(&index) = indices[..n_pixels].iter().find(|&&index| index as usize >= pallete.len()) {
+            return Err(BMPError::PaletteIndexOutOfRange{index: index as usize, palette_len: pallete.len()});
+        }
+
+        let mode = match self.compression {
+            CompressionType::RLE4 => RLEMode::Four,
+            CompressionType::RLE8 => RLEMode::Eight,
+            _ => {
+                self.write_header(dest, width, height, pallete, self.row_size(width) as u64 * height as u64)?;
+
+                let mut row = vec![0; self.row_size(width)];
+                for y in 0..height as usize {
+                    let y = if self.top_down { y } else { height as usize - 1 - y };
+
+                    encode_indexed_row(&indices[y * width as usize..(y + 1) * width as usize], self.bpp, &mut row);
+                    dest.write_all(&row)?;
+                }
+
+                return Ok(());
+            },
+        };
+
+        if self.top_down {
+            return Err(BMPError::CompressionNotSupportedForTopDown(self.compression));
+        }
+
+        /* The size of the compressed data goes in the header, so encode it all
+         * first. */
+        let mut data = Vec::new();
+        for y in (0..height as usize).rev() {
+            rle::encode_row(mode, &indices[y * width as usize..(y + 1) * width as usize], &mut data);
+            if y > 0 {
+                data.extend_from_slice(&[0, 0]);
+            }
+        }
+        data.extend_from_slice(&[0, 1]);
+
+        self.write_header(dest, width, height, pallete, data.len() as u64)?;
+        dest.write_all(&data)?;
+
+        Ok(())
+    }
+
+    fn row_size(&self, width: u32) -> usize {
+        (width as usize * self.bpp as usize).div_ceil(32) * 4
+    }
+
+    fn dib_header_size(&self) -> u32 {
+        match self.version {
+            BMPVersion::Four => 108,
+            BMPVersion::Five => 124,
+            _ => 40,
+        }
+    }
+
+    fn masks(&self) -> (u32, u32, u32, u32) {
+        match self.bpp {
+            16 => (BMP_BITFIELD16_RED, BMP_BITFIELD16_GREEN, BMP_BITFIELD16_BLUE, 0),
+            32 => (BMP_BITFIELD32_RED, BMP_BITFIELD32_GREEN, BMP_BITFIELD32_BLUE, BMP_BITFIELD32_ALPHA),
+            _ => (0, 0, 0, 0),
+        }
+    }
+
+    fn compression(&self) -> u32 {
+        match self.compression {
+            CompressionType::RLE4 => return BI_RLE4,
+            CompressionType::RLE8 => return BI_RLE8,
+            _ => (),
+        }
+
+        match (self.bpp, self.version) {
+            (16, _) => BI_BITFIELDS,
+            (32, BMPVersion::Four) | (32, BMPVersion::Five) => BI_BITFIELDS,
+            _ => BI_RGB,
+        }
+    }
+
+    fn write_header<W: Write>(&self, dest: &mut W, width: u32, height: u32, pallete: &[PalletePixel], image_size: u64) -> Result<(), BMPError> {
+        if width == 0 || width > i32::MAX as u32 {
+            return Err(BMPError::InvalidWidth(width as i32));
+        }
+
+        if height == 0 || height > i32::MAX as u32 {
+            return Err(BMPError::InvalidHeight(height as i32));
+        }
+
+        let compression = self.compression();
+        let (red_mask, green_mask, blue_mask, alpha_mask) = self.masks();
+        /* Only v3 headers need the masks to follow them. */
+        let n_mask_bytes = match self.version {
+            BMPVersion::Three if compression == BI_BITFIELDS => 12,
+            _ => 0,
+        };
+        let pixel_offset = 14 + self.dib_header_size() as u64 + n_mask_bytes + 4 * pallete.len() as u64;
+
+        dest.write_all(b"BM")?;
+        dest.write_u32::<LittleEndian>((pixel_offset + image_size).min(u32::MAX as u64) as u32)?;
+        dest.write_u32::<LittleEndian>(0)?;
+        dest.write_u32::<LittleEndian>(pixel_offset as u32)?;
+
+        dest.write_u32::<LittleEndian>(self.dib_header_size())?;
+        dest.write_i32::<LittleEndian>(width as i32)?;
+        dest.write_i32::<LittleEndian>(if self.top_down { -(height as i32) } else { height as i32 })?;
+        dest.write_u16::<LittleEndian>(1)?;
+        dest.write_u16::<LittleEndian>(self.bpp)?;
+        dest.write_u32::<LittleEndian>(compression)?;
+        dest.write_u32::<LittleEndian>(image_size.min(u32::MAX as u64) as u32)?;
+        for resolution in &[self.x_resolution, self.y_resolution] {
+            let pixels_per_meter = resolution.map_or(0, |resolution| resolution.pixels_per_meter);
+            dest.write_i32::<LittleEndian>(pixels_per_meter.min(i32::MAX as u32) as i32)?;
+        }
+        dest.write_u32::<LittleEndian>(pallete.len() as u32)?;
+        dest.write_u32::<LittleEndian>(0)?;
+
+        match self.version {
+            BMPVersion::Three => {
+                if compression == BI_BITFIELDS {
+                    dest.write_u32::<LittleEndian>(red_mask)?;
+                    dest.write_u32::<LittleEndian>(green_mask)?;
+                    dest.write_u32::<LittleEndian>(blue_mask)?;
+                }
+            },
+            _ => {
+                dest.write_u32::<LittleEndian>(red_mask)?;
+                dest.write_u32::<LittleEndian>(green_mask)?;
+                dest.write_u32::<LittleEndian>(blue_mask)?;
+                dest.write_u32::<LittleEndian>(alpha_mask)?;
+                dest.write_u32::<LittleEndian>(LCS_SRGB)?;
+                /* The endpoints and gammas are unused for sRGB. */
+                dest.write_all(&[0; 48])?;
+
+                if self.version == BMPVersion::Five {
+                    dest.write_u32::<LittleEndian>(LCS_GM_IMAGES)?;
+                    /* No colour profile. */
+                    dest.write_all(&[0; 12])?;
+                }
+            },
+        }
+
+        for entry in pallete {
+            dest.write_all(&[entry.blue, entry.green, entry.red, 0])?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_row(&self, pixels: &[Pixel], row: &mut [u8]) {
+        match self.bpp {
+            16 => {
+                for (px, bytes) in pixels.iter().zip(row.chunks_exact_mut(2)) {
+                    let value = (px.red >> 27) << 11 | (px.green >> 26) << 5 | px.blue >> 27;
+                    bytes.copy_from_slice(&(value as u16).to_le_bytes());
+                }
+            },
+            24 => {
+                for (px, bytes) in pixels.iter().zip(row.chunks_exact_mut(3)) {
+                    bytes.copy_from_slice(&[(px.blue >> 24) as u8, (px.green >> 24) as u8, (px.red >> 24) as u8]);
+                }
+            },
+            32 => {
+                for (px, bytes) in pixels.iter().zip(row.chunks_exact_mut(4)) {
+                    bytes.copy_from_slice(&[(px.blue >> 24) as u8, (px.green >> 24) as u8,
+                                            (px.red >> 24) as u8, (px.alpha >> 24) as u8]);
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+/* Packs the indices into the row, leftmost pixel in the most significant bits. */
+fn encode_indexed_row(indices: &[u8], bpp: u16, row: &mut [u8]) {
+    let pixels_per_byte = 8 / bpp as usize;
+
+    for byte in row.iter_mut() {
+        *byte = 0;
+    }
+
+    for (x, &index) in indices.iter().enumerate() {
+        let shift = 8 - bpp as usize * (x % pixels_per_byte + 1);
+
+        row[x / pixels_per_byte] |= index << shift;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use BMPReader;
+    use ColorSpace;
+    use PixelFormat;
+
+    fn rgba(red: u8, green: u8, blue: u8, alpha: u8) -> Pixel {
+        Pixel {
+            red: red as u32 * 0x01010101,
+            green: green as u32 * 0x01010101,
+            blue: blue as u32 * 0x01010101,
+            alpha: alpha as u32 * 0x01010101,
+        }
+    }
+
+    fn round_trip(writer: &BMPWriter, width: u32, height: u32, pixels: &[Pixel]) -> Vec<u8> {
+        let mut bmp = Vec::new();
+        writer.write(&mut bmp, width, height, pixels).unwrap();
+
+        let mut source = Cursor::new(bmp);
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!((reader.get_width(), reader.get_height()), (width as usize, height as usize));
+
+        reader.decode_to_vec(PixelFormat::RGBA8).unwrap()
+    }
+
+    fn pixels() -> Vec<Pixel> {
+        vec![rgba(0xff, 0, 0, 0xff), rgba(0, 0xff, 0, 0x80), rgba(0, 0, 0xff, 0),
+             rgba(0xff, 0xff, 0xff, 0xff), rgba(0, 0, 0, 0xff), rgba(0x84, 0x88, 0x8c, 0x40)]
+    }
+
+    #[test]
+    fn test_round_trip_true_color() {
+        for &version in &[BMPVersion::Three, BMPVersion::Four, BMPVersion::Five] {
+            for &top_down in &[false, true] {
+                let mut writer = BMPWriter::new(version, 24).unwrap();
+                writer.set_top_down(top_down);
+                assert_eq!(round_trip(&writer, 3, 2, &pixels()),
+                           vec![0xff, 0, 0, 0xff,  0, 0xff, 0, 0xff,  0, 0, 0xff, 0xff,
+                                0xff, 0xff, 0xff, 0xff,  0, 0, 0, 0xff,  0x84, 0x88, 0x8c, 0xff]);
+            }
+        }
+
+        let writer = BMPWriter::new(BMPVersion::Five, 32).unwrap();
+        assert_eq!(round_trip(&writer, 3, 2, &pixels()),
+                   vec![0xff, 0, 0, 0xff,  0, 0xff, 0, 0x80,  0, 0, 0xff, 0,
+                        0xff, 0xff, 0xff, 0xff,  0, 0, 0, 0xff,  0x84, 0x88, 0x8c, 0x40]);
+
+        let writer = BMPWriter::new(BMPVersion::Three, 32).unwrap();
+        assert_eq!(round_trip(&writer, 3, 2, &pixels())[4..8], [0, 0xff, 0, 0xff]);
+
+        for &version in &[BMPVersion::Three, BMPVersion::Four] {
+            let writer = BMPWriter::new(version, 16).unwrap();
+            assert_eq!(round_trip(&writer, 3, 2, &pixels())[20..24], [0x84, 0x8a, 0x8c, 0xff]);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_pallete() {
+        let pallete = [PalletePixel{red: 0, green: 0, blue: 0},
+                       PalletePixel{red: 0xff, green: 0, blue: 0},
+                       PalletePixel{red: 0xff, green: 0xff, blue: 0xff}];
+
+        for &bpp in &[1, 4, 8] {
+            let mut writer = BMPWriter::new(BMPVersion::Three, bpp).unwrap();
+            let pallete = pallete[..pallete.len().min(1 << bpp)].to_vec();
+            writer.set_palette(pallete).unwrap();
+
+            let expected = if bpp == 1 {
+                vec![0xff, 0, 0, 0xff,  0, 0, 0, 0xff,  0, 0, 0, 0xff,
+                     0xff, 0, 0, 0xff,  0, 0, 0, 0xff,  0xff, 0, 0, 0xff]
+            } else {
+                vec![0xff, 0, 0, 0xff,  0, 0, 0, 0xff,  0, 0, 0, 0xff,
+                     0xff, 0xff, 0xff, 0xff,  0, 0, 0, 0xff,  0xff, 0xff, 0xff, 0xff]
+            };
+            assert_eq!(round_trip(&writer, 3, 2, &pixels()), expected);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_quantized() {
+        let expected = vec![0xff, 0, 0, 0xff,  0, 0xff, 0, 0xff,  0, 0, 0xff, 0xff,
+                            0xff, 0xff, 0xff, 0xff,  0, 0, 0, 0xff,  0x84, 0x88, 0x8c, 0xff];
+
+        for &dither in &[false, true] {
+            let mut writer = BMPWriter::new(BMPVersion::Three, 8).unwrap();
+            writer.set_dithering(dither);
+            assert_eq!(round_trip(&writer, 3, 2, &pixels()), expected);
+        }
+
+        let gradient: Vec<Pixel> = (0..64 * 4).map(|i| rgba(i as u8, 0x80, 0xff - i as u8, 0xff)).collect();
+        let mut writer = BMPWriter::new(BMPVersion::Three, 4).unwrap();
+        writer.set_dithering(true);
+        let decoded = round_trip(&writer, 64, 4, &gradient);
+
+        for (px, bytes) in gradient.iter().zip(decoded.chunks(4)) {
+            assert!(((px.red >> 24) as i32 - bytes[0] as i32).abs() < 48);
+        }
+    }
+
+    #[test]
+    fn test_color_space() {
+        for &(version, color_space) in &[(BMPVersion::Three, None),
+                                         (BMPVersion::Four, Some(ColorSpace::SRGB)),
+                                         (BMPVersion::Five, Some(ColorSpace::SRGB))] {
+            let mut bmp = Vec::new();
+            BMPWriter::new(version, 24).unwrap().write(&mut bmp, 3, 2, &pixels()).unwrap();
+
+            assert_eq!(::probe(&mut Cursor::new(bmp)).unwrap().color_space, color_space);
+        }
+    }
+
+    #[test]
+    fn test_resolution() {
+        let mut writer = BMPWriter::new(BMPVersion::Three, 24).unwrap();
+        let mut bmp = Vec::new();
+        writer.write(&mut bmp, 3, 2, &pixels()).unwrap();
+        assert_eq!(::probe(&mut Cursor::new(bmp)).unwrap().resolution(), (None, None));
+
+        writer.set_resolution(Some(Resolution::from_dpi(300.0)), Some(Resolution{pixels_per_meter: 2835}));
+        let mut bmp = Vec::new();
+        writer.write(&mut bmp, 3, 2, &pixels()).unwrap();
+        assert_eq!(::probe(&mut Cursor::new(bmp)).unwrap().resolution(),
+                   (Some(Resolution{pixels_per_meter: 11811}), Some(Resolution{pixels_per_meter: 2835})));
+    }
+
+    #[test]
+    fn test_write_indexed() {
+        let pallete = [PalletePixel{red: 0, green: 0, blue: 0},
+                       PalletePixel{red: 0xff, green: 0xff, blue: 0xff}];
+        let writer = BMPWriter::new(BMPVersion::Three, 1).unwrap();
+        let mut bmp = Vec::new();
+
+        writer.write_indexed(&mut bmp, 9, 1, &pallete, &[1, 0, 1, 1, 0, 0, 0, 0, 1]).unwrap();
+        assert_eq!(bmp.len(), 14 + 40 + 8 + 4);
+        assert_eq!(bmp[62..], [0b1011_0000, 0b1000_0000, 0, 0]);
+
+        match writer.write_indexed(&mut Vec::new(), 1, 1, &pallete, &[2]) {
+            Err(BMPError::PaletteIndexOutOfRange{index: 2, palette_len: 2}) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_write_rle() {
+        let pallete = (0..16).map(|i| PalletePixel{red: i * 16, green: 0, blue: 0}).collect::<Vec<_>>();
+        let indices = (0..7 * 3).map(|i| if i % 7 < 4 { 3 } else { i as u8 % 16 }).collect::<Vec<_>>();
+        let expected = indices.iter().flat_map(|&i| vec![i * 16, 0, 0, 0xff]).collect::<Vec<_>>();
+
+        for &(bpp, compression) in &[(4, CompressionType::RLE4), (8, CompressionType::RLE8)] {
+            let mut writer = BMPWriter::new(BMPVersion::Three, bpp).unwrap();
+            writer.set_compression(compression).unwrap();
+
+            let mut bmp = Vec::new();
+            writer.write_indexed(&mut bmp, 7, 3, &pallete, &indices).unwrap();
+            assert_eq!(&bmp[bmp.len() - 2..], &[0, 1]);
+
+            let mut source = Cursor::new(bmp);
+            let mut reader = BMPReader::new(&mut source).unwrap();
+            assert_eq!(reader.header().compression, compression);
+            assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(), expected);
+        }
+
+        let mut writer = BMPWriter::new(BMPVersion::Three, 8).unwrap();
+        assert!(writer.set_compression(CompressionType::RLE4).is_err());
+        assert!(writer.set_compression(CompressionType::Bitfields).is_err());
+
+        writer.set_compression(CompressionType::RLE8).unwrap();
+        writer.set_top_down(true);
+        match writer.write_indexed(&mut Vec::new(), 7, 3, &pallete, &indices) {
+            Err(BMPError::CompressionNotSupportedForTopDown(CompressionType::RLE8)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_invalid_writer() {
+        assert!(BMPWriter::new(BMPVersion::Two, 24).is_err());
+        assert!(BMPWriter::new(BMPVersion::Three, 12).is_err());
+
+        let writer = BMPWriter::new(BMPVersion::Three, 8).unwrap();
+        assert!(writer.write(&mut Vec::new(), 0, 1, &pixels()).is_err());
+        assert!(writer.write(&mut Vec::new(), 3, 3, &pixels()).is_err());
+    }
+}
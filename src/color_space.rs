@@ -0,0 +1,268 @@
+use bmp_pixels::{Pixel,from_unit,linear_to_srgb};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{self,Read};
+
+const LCS_CALIBRATED_RGB: u32 = 0;
+const LCS_SRGB: u32 = 0x73524742;
+const LCS_WINDOWS_COLOR_SPACE: u32 = 0x57696e20;
+const PROFILE_LINKED: u32 = 0x4c494e4b;
+const PROFILE_EMBEDDED: u32 = 0x4d424544;
+
+/* From linear light XYZ to linear light sRGB, with a D65 white point. */
+const XYZ_TO_SRGB: [[f64; 3]; 3] = [
+    [ 3.2404542, -1.5371385, -0.4985314],
+    [-0.9692660,  1.8760108,  0.0415560],
+    [ 0.0556434, -0.2040259,  1.0572252],
+];
+
+const LCS_GM_BUSINESS: u32 = 1;
+const LCS_GM_GRAPHICS: u32 = 2;
+const LCS_GM_IMAGES: u32 = 4;
+const LCS_GM_ABS_COLORIMETRIC: u32 = 8;
+
+/* A colour in the CIE 1931 XYZ colour space. */
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct CIEXYZ {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl CIEXYZ {
+    /* Reads three 2.30 fixed point values. */
+    fn read<R: Read>(source: &mut R) -> Result<CIEXYZ, io::Error> {
+        let mut read = || -> Result<f64, io::Error> {
+            Ok(source.read_u32::<LittleEndian>()? as f64 / (1u64 << 30) as f64)
+        };
+
+        Ok(CIEXYZ {
+            x: read()?,
+            y: read()?,
+            z: read()?,
+        })
+    }
+}
+
+/* How the RGB values of a v4 or v5 file are to be interpreted. */
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ColorSpace {
+    /* RGB defined by the XYZ coordinates of its red, green and blue
+     * endpoints and the gamma of each channel. */
+    CalibratedRGB {
+        red: CIEXYZ,
+        green: CIEXYZ,
+        blue: CIEXYZ,
+        gamma_red: f64,
+        gamma_green: f64,
+        gamma_blue: f64,
+    },
+    SRGB,
+    /* The system default colour space, which is sRGB. */
+    Windows,
+    /* The colour space is given by an ICC profile in another file. */
+    LinkedProfile,
+    /* The colour space is given by an ICC profile in the file. */
+    EmbeddedProfile,
+    /* A colour space type this crate does not know about. */
+    Unknown(u32),
+}
+
+/* The rendering intent of a v5 header, named after the ICC intents that the
+ * LCS_GM_BUSINESS, LCS_GM_GRAPHICS, LCS_GM_IMAGES and LCS_GM_ABS_COLORIMETRIC
+ * values stand for. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum RenderingIntent {
+    Saturation,
+    RelativeColorimetric,
+    Perceptual,
+    AbsoluteColorimetric,
+    Unknown(u32),
+}
+
+impl RenderingIntent {
+    pub fn from_u32(val: u32) -> RenderingIntent {
+        match val {
+            LCS_GM_BUSINESS => RenderingIntent::Saturation,
+            LCS_GM_GRAPHICS => RenderingIntent::RelativeColorimetric,
+            LCS_GM_IMAGES => RenderingIntent::Perceptual,
+            LCS_GM_ABS_COLORIMETRIC => RenderingIntent::AbsoluteColorimetric,
+            val => RenderingIntent::Unknown(val),
+        }
+    }
+}
+
+/* Where the profile data of a v5 header is, as an offset from the start of
+ * the file and a size in bytes. */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct ProfileLocation {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/* An ICC profile, either embedded in the file or the file name of a linked
+ * one. Linked file names are read as Latin-1, which only differs from the
+ * Windows-1252 they are meant to be in for bytes 0x80 to 0x9f. */
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Profile {
+    Embedded(Vec<u8>),
+    Linked(String),
+}
+
+impl ColorSpace {
+    /* Reads the 52 bytes that describe the colour space in a v4 header:
+     * the type, the endpoints and the 16.16 fixed point gammas. The
+     * endpoints and gammas are only used by calibrated RGB. */
+    pub fn read<R: Read>(source: &mut R) -> Result<ColorSpace, io::Error> {
+        let cs_type = source.read_u32::<LittleEndian>()?;
+        let red = CIEXYZ::read(source)?;
+        let green = CIEXYZ::read(source)?;
+        let blue = CIEXYZ::read(source)?;
+        let mut gammas = [0.0; 3];
+        for gamma in gammas.iter_mut() {
+            *gamma = source.read_u32::<LittleEndian>()? as f64 / 65536.0;
+        }
+
+        Ok(match cs_type {
+            LCS_CALIBRATED_RGB => ColorSpace::CalibratedRGB {
+                red,
+                green,
+                blue,
+                gamma_red: gammas[0],
+                gamma_green: gammas[1],
+                gamma_blue: gammas[2],
+            },
+            LCS_SRGB => ColorSpace::SRGB,
+            LCS_WINDOWS_COLOR_SPACE => ColorSpace::Windows,
+            PROFILE_LINKED => ColorSpace::LinkedProfile,
+            PROFILE_EMBEDDED => ColorSpace::EmbeddedProfile,
+            cs_type => ColorSpace::Unknown(cs_type),
+        })
+    }
+}
+
+fn determinant(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+    m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+    m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/* Converts pixels in calibrated RGB to sRGB: each channel is raised to its
+ * gamma to get linear light, the endpoints take it to XYZ, and from there it
+ * goes to sRGB. There is no chromatic adaptation, so the white point is taken
+ * to be that of sRGB. A gamma of zero is taken to mean linear light. */
+pub struct SRGBTransform {
+    matrix: [[f64; 3]; 3],
+    gamma: [f64; 3],
+}
+
+impl SRGBTransform {
+    /* Only calibrated RGB has a transform, other colour spaces are either sRGB
+     * already or need a colour management system. Many writers leave the type
+     * as calibrated RGB with all endpoints zero, and endpoints that are not
+     * independent cannot describe an RGB colour space either, so there is no
+     * transform for those. */
+    pub fn new(color_space: &ColorSpace) -> Option<SRGBTransform> {
+        let (red, green, blue, gamma) = match *color_space {
+            ColorSpace::CalibratedRGB { red, green, blue, gamma_red, gamma_green, gamma_blue } =>
+                (red, green, blue, [gamma_red, gamma_green, gamma_blue]),
+            _ => return None,
+        };
+
+        let endpoints = [[red.x, green.x, blue.x],
+                         [red.y, green.y, blue.y],
+                         [red.z, green.z, blue.z]];
+        if determinant(&endpoints).abs() < 1e-6 {
+            return None;
+        }
+
+        let mut matrix = [[0.0; 3]; 3];
+        for (i, matrix_row) in matrix.iter_mut().enumerate() {
+            for (j, value) in matrix_row.iter_mut().enumerate() {
+                *value = (0..3).map(|k| XYZ_TO_SRGB[i][k] * endpoints[k][j]).sum();
+            }
+        }
+
+        Some(SRGBTransform {
+            matrix,
+            gamma: [0, 1, 2].map(|i| if gamma[i] == 0.0 { 1.0 } else { gamma[i] }),
+        })
+    }
+
+    pub fn apply(&self, px: &mut Pixel) {
+        let channels = [px.red, px.green, px.blue];
+        let mut linear = [0.0; 3];
+        for (i, value) in linear.iter_mut().enumerate() {
+            *value = (channels[i] as f64 / u32::MAX as f64).powf(self.gamma[i]);
+        }
+
+        let srgb = self.matrix.map(|row| {
+            let value: f64 = row.iter().zip(linear.iter()).map(|(a, b)| a * b).sum();
+            from_unit(linear_to_srgb(value.clamp(0.0, 1.0)))
+        });
+
+        px.red = srgb[0];
+        px.green = srgb[1];
+        px.blue = srgb[2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    #[test]
+    fn test_calibrated_rgb() {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(LCS_CALIBRATED_RGB).unwrap();
+        for &value in &[0.64, 0.33, 0.03, 0.3, 0.6, 0.1, 0.15, 0.06, 0.79] {
+            data.write_u32::<LittleEndian>((value * (1u64 << 30) as f64) as u32).unwrap();
+        }
+        for &gamma in &[0x23333, 0x20000, 0x10000] {
+            data.write_u32::<LittleEndian>(gamma).unwrap();
+        }
+
+        match ColorSpace::read(&mut &data[..]).unwrap() {
+            ColorSpace::CalibratedRGB { red, blue, gamma_red, gamma_green, gamma_blue, .. } => {
+                assert!((red.x - 0.64).abs() < 1e-6 && (blue.z - 0.79).abs() < 1e-6);
+                assert!((gamma_red - 2.2).abs() < 1e-4);
+                assert_eq!((gamma_green, gamma_blue), (2.0, 1.0));
+            },
+            color_space => panic!("{:?}", color_space),
+        }
+    }
+
+    #[test]
+    fn test_degenerate_endpoints() {
+        let xyz = |x, y, z| CIEXYZ{x, y, z};
+        let calibrated = |red, green, blue| ColorSpace::CalibratedRGB {
+            red,
+            green,
+            blue,
+            gamma_red: 0.0,
+            gamma_green: 0.0,
+            gamma_blue: 0.0,
+        };
+
+        let zero = xyz(0.0, 0.0, 0.0);
+        assert!(SRGBTransform::new(&calibrated(zero, zero, zero)).is_none());
+        assert!(SRGBTransform::new(&calibrated(xyz(0.64, 0.33, 0.03), xyz(0.64, 0.33, 0.03), xyz(0.15, 0.06, 0.79))).is_none());
+        assert!(SRGBTransform::new(&calibrated(xyz(0.64, 0.33, 0.03), xyz(0.3, 0.6, 0.1), xyz(0.15, 0.06, 0.79))).is_some());
+    }
+
+    #[test]
+    fn test_types() {
+        for &(cs_type, ref expected) in &[(LCS_SRGB, ColorSpace::SRGB),
+                                          (LCS_WINDOWS_COLOR_SPACE, ColorSpace::Windows),
+                                          (PROFILE_LINKED, ColorSpace::LinkedProfile),
+                                          (PROFILE_EMBEDDED, ColorSpace::EmbeddedProfile),
+                                          (7, ColorSpace::Unknown(7))] {
+            let mut data = [0; 52];
+            (&mut data[..4]).write_u32::<LittleEndian>(cs_type).unwrap();
+
+            assert_eq!(ColorSpace::read(&mut &data[..]).unwrap(), *expected);
+        }
+    }
+}
@@ -1,36 +1,126 @@
 extern crate byteorder;
+#[cfg(feature = "png")]
+extern crate png;
 
 mod bitreader;
 mod bmp_header;
+mod bmp_options;
 mod bmp_pixels;
+mod bmp_writer;
+mod color_space;
+mod huffman;
+mod quantizer;
+mod rle;
 
-use bmp_header::{BMPError};
-use bmp_pixels::{Pixel,Pixels};
-use std::io::{self,Read,Seek};
+pub use bmp_header::{BMPError,BMPHeader,BMPVersion,CompressionType,EmbeddedFormat,EmbeddedImage,Resolution};
+pub use bmp_options::{BMPOptions,InvalidPaletteIndex,UndefinedPixels};
+pub use bmp_pixels::{Pixel,PixelFormat,PalletePixel};
+pub use bmp_writer::BMPWriter;
+pub use color_space::{CIEXYZ,ColorSpace,Profile,ProfileLocation,RenderingIntent};
+pub use quantizer::{map_to_palette,median_cut,quantize};
+
+use bmp_header::pixel_data_size;
+use bmp_pixels::{Pixels,read_pallete};
+use color_space::SRGBTransform;
+use std::io::{self,Read,Seek,SeekFrom};
+
+/* Reads only the file and DIB headers, without looking at the pixel data. */
+pub fn probe<R: Read>(source: &mut R) -> Result<BMPHeader, BMPError> {
+    BMPHeader::from_buffer(source)
+}
+
+/* Like probe(), but also reads the palette that follows the headers. */
+pub fn probe_with_palette<R: Read>(source: &mut R) -> Result<(BMPHeader, Vec<PalletePixel>), BMPError> {
+    let header = BMPHeader::from_buffer(source)?;
+    let pallete = read_pallete(source, &header)?;
+
+    Ok((header, pallete))
+}
+
+/* Reads the ICC profile of a v5 header, if it has one. Profiles that overlap
+ * the pixel data are rejected, and so are profiles that reach past its start
+ * when the size of compressed pixel data is not given, as they cannot be
+ * checked. */
+pub fn read_profile<R: Read + Seek>(source: &mut R, header: &BMPHeader) -> Result<Option<Profile>, BMPError> {
+    let location = match header.profile {
+        Some(location) => location,
+        None => return Ok(None),
+    };
+
+    if header.pixel_offset < location.offset + location.size {
+        match pixel_data_size(header) {
+            Some(size) if location.offset < header.pixel_offset.saturating_add(size) => {
+                return Err(BMPError::ProfileOverlapsPixelData{offset: location.offset, size: location.size});
+            },
+            Some(_) => (),
+            None => return Err(BMPError::PixelDataSizeUnknown(header.compression)),
+        }
+    }
+
+    let mut bytes = Vec::new();
+    source.seek(SeekFrom::Start(location.offset))?;
+    source.take(location.size).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < location.size {
+        return Err(BMPError::IOError(io::Error::from(io::ErrorKind::UnexpectedEof)));
+    }
+
+    match header.color_space {
+        Some(ColorSpace::LinkedProfile) => {
+            let name = bytes.iter().take_while(|&&byte| byte != 0).map(|&byte| byte as char).collect();
+            Ok(Some(Profile::Linked(name)))
+        },
+        _ => Ok(Some(Profile::Embedded(bytes))),
+    }
+}
 
 pub struct BMPReader<'a, R: Read + Seek + 'a> {
     pixels: Pixels<'a, R>,
+    header: BMPHeader,
     bottom_up: bool,
     width: usize,
     height: usize,
+    row: Vec<Pixel>,
     x: usize,
     y: usize,
+    transform: Option<SRGBTransform>,
 }
 
 impl<'a, R: Read + Seek + 'a> BMPReader<'a, R> {
-    pub fn new(mut source: &mut R) -> Result<BMPReader<R>, BMPError> {
-        let (pixels, width, height) = Pixels::new(source)?;
+    pub fn new(source: &'a mut R) -> Result<BMPReader<'a, R>, BMPError> {
+        BMPReader::with_options(source, BMPOptions::default())
+    }
+
+    pub fn with_options(source: &'a mut R, options: BMPOptions) -> Result<BMPReader<'a, R>, BMPError> {
+        let (pixels, header) = Pixels::new(source, &options)?;
+        let width = header.width as usize;
+        /* Raw CMYK, linear light and embedded images are not calibrated RGB
+         * values, even if the header says so. */
+        let calibrated = options.convert_calibrated_rgb &&
+                         !(header.is_cmyk() && options.raw_cmyk) &&
+                         !(header.bpp == 64 && options.linear_64bpp) &&
+                         header.embedded_image().is_none();
+        let transform = match header.color_space {
+            Some(ref color_space) if calibrated => SRGBTransform::new(color_space),
+            _ => None,
+        };
 
         Ok(BMPReader {
             pixels,
-            width: width as usize,
-            height: height.abs() as usize,
-            bottom_up: height > 0,
-            x: 0,
+            width,
+            height: header.height.unsigned_abs() as usize,
+            bottom_up: header.height > 0,
+            row: vec![Pixel{red: 0, green: 0, blue: 0, alpha: 0}; width],
+            x: width,
             y: 0,
+            transform,
+            header,
         })
     }
 
+    pub fn header(&self) -> &BMPHeader {
+        &self.header
+    }
+
     pub fn get_width(&self) -> usize {
         self.width
     }
@@ -39,40 +129,909 @@ impl<'a, R: Read + Seek + 'a> BMPReader<'a, R> {
         self.height
     }
 
-    fn get_y(&self) -> usize {
+    /* Decodes the next row into the first get_width() pixels of row and
+     * returns its y coordinate, or None once all rows have been read. Rows
+     * come in file order, so y counts up from the bottom of the image in
+     * bottom-up files and down from the top in top-down ones. After an error
+     * there are no more rows, as the position in the pixel data is lost. */
+    pub fn next_row(&mut self, row: &mut [Pixel]) -> Result<Option<usize>, BMPError> {
+        if row.len() < self.width {
+            return Err(BMPError::BufferTooSmall(self.width, row.len()));
+        }
+
+        /* Drop the rest of a row that is partially read by the iterator. */
+        self.x = self.width;
+
+        if self.y >= self.height {
+            return Ok(None);
+        }
+
+        let y = self.get_y(self.y);
+        if let Err(err) = self.pixels.read_row(y, &mut row[..self.width]) {
+            self.y = self.height;
+            return Err(err);
+        }
+        if let Some(ref transform) = self.transform {
+            for px in row[..self.width].iter_mut() {
+                transform.apply(px);
+            }
+        }
+        self.y += 1;
+
+        Ok(Some(y))
+    }
+
+    /* Like next_row(), but writes the row as bytes in the given format. */
+    pub fn next_row_bytes(&mut self, buffer: &mut [u8], format: PixelFormat) -> Result<Option<usize>, BMPError> {
+        let row_size = self.width * format.bytes_per_pixel();
+
+        if buffer.len() < row_size {
+            return Err(BMPError::BufferTooSmall(row_size, buffer.len()));
+        }
+
+        let mut row = std::mem::take(&mut self.row);
+        let y = self.next_row(&mut row);
+
+        if let Ok(Some(_)) = y {
+            for (px, bytes) in row.iter().zip(buffer.chunks_exact_mut(format.bytes_per_pixel())) {
+                px.write_bytes(bytes, format);
+            }
+        }
+        self.row = row;
+
+        y
+    }
+
+    /* Decodes the rows that have not been read yet into buffer, top row first,
+     * with no padding between rows. */
+    pub fn decode_into(&mut self, buffer: &mut [u8], format: PixelFormat) -> Result<(), BMPError> {
+        let row_size = self.width * format.bytes_per_pixel();
+        let size = self.decoded_size(format)?;
+
+        if buffer.len() < size {
+            return Err(BMPError::BufferTooSmall(size, buffer.len()));
+        }
+
+        while self.y < self.height {
+            let top = self.height - 1 - self.get_y(self.y);
+
+            self.next_row_bytes(&mut buffer[top * row_size..(top + 1) * row_size], format)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn decode_to_vec(&mut self, format: PixelFormat) -> Result<Vec<u8>, BMPError> {
+        let size = self.decoded_size(format)?;
+        let mut buffer = Vec::new();
+
+        if buffer.try_reserve_exact(size).is_err() {
+            return Err(self.too_large());
+        }
+        buffer.resize(size, 0);
+        self.decode_into(&mut buffer, format)?;
+
+        Ok(buffer)
+    }
+
+    /* The size of the whole decoded image, which for a hostile header may not
+     * fit in a usize. */
+    fn decoded_size(&self, format: PixelFormat) -> Result<usize, BMPError> {
+        self.width.checked_mul(format.bytes_per_pixel())
+                  .and_then(|row_size| row_size.checked_mul(self.height))
+                  .ok_or_else(|| self.too_large())
+    }
+
+    fn too_large(&self) -> BMPError {
+        BMPError::ImageTooLarge{width: self.width as u32, height: self.height as u32}
+    }
+
+    /* Rows are stored in file order, but y is counted from the bottom of the
+     * image. */
+    fn get_y(&self, row: usize) -> usize {
         if self.bottom_up {
-            self.y
+            row
         } else {
-            self.height - self.y
+            self.height - 1 - row
         }
     }
 }
 
 impl<'a, R: Read + Seek + 'a> Iterator for BMPReader<'a, R> {
-    type Item = (usize, usize, Result<Pixel, io::Error>);
+    type Item = (usize, usize, Result<Pixel, BMPError>);
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.x >= self.width {
-            self.x = 0;
             if self.y >= self.height {
                 return None;
-            } else {
-                self.y += 1;
-                if let Err(err) = self.pixels.seek_to_byte_boundary(4) {
-                    return Some((self.x, self.get_y(), Err(err)));
+            }
+
+            let y = self.get_y(self.y);
+            if let Err(err) = self.pixels.read_row(y, &mut self.row) {
+                /* Report the error at the column it names, if it names one. */
+                let x = match err {
+                    BMPError::TruncatedPixelData { column, .. } |
+                    BMPError::InvalidHuffmanCode { column, .. } => column,
+                    _ => 0,
+                };
+
+                self.y = self.height;
+                return Some((x, y, Err(err)));
+            }
+
+            if let Some(ref transform) = self.transform {
+                for px in self.row.iter_mut() {
+                    transform.apply(px);
                 }
             }
-        } else {
-            self.x += 1;
+
+            self.x = 0;
+            self.y += 1;
         }
 
-        Some((self.x, self.get_y(), self.pixels.next_pixel()))
+        let x = self.x;
+        self.x += 1;
+
+        Some((x, self.get_y(self.y - 1), Ok(self.row[x])))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use byteorder::{LittleEndian,WriteBytesExt};
+    use std::io::{self,Cursor};
+
+    fn bmp_v3(width: i32, height: i32, bpp: u16, compression: u32, pallete: &[[u8; 4]], data: &[u8]) -> Vec<u8> {
+        let pixel_offset = 14 + 40 + 4 * pallete.len() as u32;
+        let mut bmp = Vec::new();
+
+        bmp.extend_from_slice(b"BM");
+        bmp.write_u32::<LittleEndian>(pixel_offset + data.len() as u32).unwrap();
+        bmp.write_u32::<LittleEndian>(0).unwrap();
+        bmp.write_u32::<LittleEndian>(pixel_offset).unwrap();
+
+        bmp.write_u32::<LittleEndian>(40).unwrap();
+        bmp.write_i32::<LittleEndian>(width).unwrap();
+        bmp.write_i32::<LittleEndian>(height).unwrap();
+        bmp.write_u16::<LittleEndian>(1).unwrap();
+        bmp.write_u16::<LittleEndian>(bpp).unwrap();
+        bmp.write_u32::<LittleEndian>(compression).unwrap();
+        bmp.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+        bmp.write_u32::<LittleEndian>(0).unwrap();
+        bmp.write_u32::<LittleEndian>(0).unwrap();
+        bmp.write_u32::<LittleEndian>(pallete.len() as u32).unwrap();
+        bmp.write_u32::<LittleEndian>(0).unwrap();
+
+        for entry in pallete {
+            bmp.extend_from_slice(entry);
+        }
+        bmp.extend_from_slice(data);
+
+        bmp
+    }
+
+    /* An OS/2 2.x file with the header cut short to header_size bytes. */
+    fn bmp_os2(header_size: usize, width: i32, height: i32, bpp: u16, compression: u32, pallete: &[[u8; 4]], data: &[u8]) -> Vec<u8> {
+        let mut bmp = bmp_v3(width, height, bpp, compression, pallete, data);
+        let pixel_offset = 14 + header_size as u32 + 4 * pallete.len() as u32;
+
+        bmp.splice(54..54, vec![0; 24]);
+        bmp.drain(14 + header_size..14 + 64);
+        (&mut bmp[10..14]).write_u32::<LittleEndian>(pixel_offset).unwrap();
+        (&mut bmp[14..18]).write_u32::<LittleEndian>(header_size as u32).unwrap();
+
+        bmp
+    }
+
+    fn read_red(bmp: Vec<u8>) -> Vec<(usize, usize, u8)> {
+        read_red_with_options(bmp, BMPOptions::default())
+    }
+
+    fn read_red_with_options(bmp: Vec<u8>, options: BMPOptions) -> Vec<(usize, usize, u8)> {
+        let mut source = Cursor::new(bmp);
+        let reader = BMPReader::with_options(&mut source, options).unwrap();
+
+        reader.map(|(x, y, px)| (x, y, (px.unwrap().red >> 24) as u8)).collect()
+    }
+
+    #[test]
+    fn test_read_uncompressed() {
+        let data = [0x00, 0x00, 0x10,  0x00, 0x00, 0x20,  0, 0,
+                    0x00, 0x00, 0x30,  0x00, 0x00, 0x40,  0, 0];
+
+        assert_eq!(read_red(bmp_v3(2, 2, 24, 0, &[], &data)),
+                   vec![(0, 0, 0x10), (1, 0, 0x20), (0, 1, 0x30), (1, 1, 0x40)]);
+        assert_eq!(read_red(bmp_v3(2, -2, 24, 0, &[], &data)),
+                   vec![(0, 1, 0x10), (1, 1, 0x20), (0, 0, 0x30), (1, 0, 0x40)]);
+    }
+
+    #[test]
+    fn test_read_rle8() {
+        let pallete = [[0, 0, 0x10, 0], [0, 0, 0x20, 0], [0, 0, 0x30, 0]];
+        let data = [3, 1, 0, 0,
+                    0, 3, 2, 0, 1, 0, 0, 1];
+
+        assert_eq!(read_red(bmp_v3(3, 2, 8, 1, &pallete, &data)),
+                   vec![(0, 0, 0x20), (1, 0, 0x20), (2, 0, 0x20),
+                        (0, 1, 0x30), (1, 1, 0x10), (2, 1, 0x20)]);
+    }
+
+    #[test]
+    fn test_read_rle4() {
+        let pallete = [[0, 0, 0x10, 0], [0, 0, 0x20, 0], [0, 0, 0x30, 0]];
+        let data = [3, 0x12, 0, 0,
+                    0, 3, 0x00, 0x20, 0, 1];
+
+        assert_eq!(read_red(bmp_v3(3, 2, 4, 2, &pallete, &data)),
+                   vec![(0, 0, 0x20), (1, 0, 0x30), (2, 0, 0x20),
+                        (0, 1, 0x10), (1, 1, 0x10), (2, 1, 0x30)]);
+    }
+
+    #[test]
+    fn test_read_rle_undefined_pixels() {
+        let pallete = [[0, 0, 0x10, 0], [0, 0, 0x20, 0]];
+        let data = [1, 0, 1, 1, 0, 1];
+        let bmp = bmp_v3(2, 2, 8, 1, &pallete, &data);
+
+        let mut source = Cursor::new(bmp.clone());
+        let pixels: Vec<Pixel> = BMPReader::new(&mut source).unwrap().map(|(_, _, px)| px.unwrap()).collect();
+        assert_eq!(pixels[2], Pixel{red: 0, green: 0, blue: 0, alpha: 0});
+
+        let options = BMPOptions{undefined_pixels: UndefinedPixels::FirstPaletteEntry, ..BMPOptions::default()};
+        assert_eq!(read_red_with_options(bmp.clone(), options),
+                   vec![(0, 0, 0x10), (1, 0, 0x20), (0, 1, 0x10), (1, 1, 0x10)]);
+
+        let color = Pixel{red: 0xffffffff, green: 0, blue: 0, alpha: 0xffffffff};
+        let options = BMPOptions{undefined_pixels: UndefinedPixels::Color(color), ..BMPOptions::default()};
+        assert_eq!(read_red_with_options(bmp, options),
+                   vec![(0, 0, 0x10), (1, 0, 0x20), (0, 1, 0xff), (1, 1, 0xff)]);
+    }
+
     #[test]
-    fn it_works() {
+    fn test_decode_to_vec() {
+        let data = [0x01, 0x02, 0x03,  0x04, 0x05, 0x06,  0, 0,
+                    0x07, 0x08, 0x09,  0x0a, 0x0b, 0x0c,  0, 0];
+
+        let mut source = Cursor::new(bmp_v3(2, 2, 24, 0, &[], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                   vec![0x09, 0x08, 0x07, 0xff,  0x0c, 0x0b, 0x0a, 0xff,
+                        0x03, 0x02, 0x01, 0xff,  0x06, 0x05, 0x04, 0xff]);
+
+        let mut source = Cursor::new(bmp_v3(2, -2, 24, 0, &[], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGB8).unwrap(),
+                   vec![0x03, 0x02, 0x01,  0x06, 0x05, 0x04,
+                        0x09, 0x08, 0x07,  0x0c, 0x0b, 0x0a]);
+    }
+
+    #[test]
+    fn test_decode_into_pallete() {
+        let pallete = [[0, 0, 0, 0], [0xff, 0xff, 0xff, 0]];
+        let data = [0b1010_0000, 0, 0, 0,
+                    0b0110_0000, 0, 0, 0];
+
+        let mut source = Cursor::new(bmp_v3(3, -2, 1, 0, &pallete, &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        let mut buffer = [0; 18];
+        assert!(reader.decode_into(&mut buffer, PixelFormat::RGB8).is_ok());
+        assert_eq!(buffer.iter().step_by(3).cloned().collect::<Vec<u8>>(),
+                   vec![0xff, 0, 0xff,
+                        0, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_next_row() {
+        let data = [0x01, 0x02, 0x03,  0x04, 0x05, 0x06,  0, 0,
+                    0x07, 0x08, 0x09,  0x0a, 0x0b, 0x0c,  0, 0];
+
+        let mut source = Cursor::new(bmp_v3(2, -2, 24, 0, &[], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        let mut row = [0; 6];
+        assert_eq!(reader.next_row_bytes(&mut row, PixelFormat::RGB8).unwrap(), Some(1));
+        assert_eq!(row, [0x03, 0x02, 0x01, 0x06, 0x05, 0x04]);
+
+        let mut row = [Pixel{red: 0, green: 0, blue: 0, alpha: 0}; 2];
+        assert_eq!(reader.next_row(&mut row).unwrap(), Some(0));
+        assert_eq!(row[1].red, 0x0c0c0c0c);
+        assert_eq!(reader.next_row(&mut row).unwrap(), None);
+    }
+
+    #[test]
+    fn test_next_row_after_error() {
+        let data = [1, 0, 0, 0,
+                    0, 0, 0, 0];
+
+        let mut source = Cursor::new(bmp_v3(2, 2, 8, 0, &[[0; 4]], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        let mut row = [0; 8];
+        match reader.next_row_bytes(&mut row, PixelFormat::RGBA8) {
+            Err(BMPError::PaletteIndexOutOfRange{index: 1, palette_len: 1}) => (),
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(reader.next_row_bytes(&mut row, PixelFormat::RGBA8).unwrap(), None);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_next_row_after_iterator() {
+        let data = [0x01, 0x02, 0x03,  0x04, 0x05, 0x06,  0, 0,
+                    0x07, 0x08, 0x09,  0x0a, 0x0b, 0x0c,  0, 0];
+
+        let mut source = Cursor::new(bmp_v3(2, 2, 24, 0, &[], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!(reader.next().unwrap().0, 0);
+
+        let mut row = [0; 8];
+        assert_eq!(reader.next_row_bytes(&mut row, PixelFormat::RGBA8).unwrap(), Some(1));
+        assert_eq!(row, [0x09, 0x08, 0x07, 0xff, 0x0c, 0x0b, 0x0a, 0xff]);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_decode_into_too_small() {
+        let data = [0, 0, 0, 0];
+
+        let mut source = Cursor::new(bmp_v3(1, 1, 24, 0, &[], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        match reader.decode_into(&mut [0; 3], PixelFormat::RGBA8) {
+            Err(BMPError::BufferTooSmall(4, 3)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mut bmp = bmp_v3(3, -2, 8, 1, &[[0, 0, 0, 0]; 3], &[0, 1]);
+        bmp[38] = 0x13;
+        bmp[39] = 0x0b;
+        bmp[50] = 2;
+
+        let mut source = Cursor::new(bmp);
+        let reader = BMPReader::new(&mut source).unwrap();
+        let header = reader.header();
+        assert_eq!(header.version, BMPVersion::Three);
+        assert_eq!(header.bpp, 8);
+        assert_eq!(header.compression, CompressionType::RLE8);
+        assert_eq!(header.n_colors, 3);
+        assert_eq!(header.pixel_offset, 66);
+        assert_eq!(header.image_size, 2);
+        assert_eq!(header.x_pixels_per_meter, 2835);
+        assert_eq!(header.y_pixels_per_meter, 0);
+        assert_eq!(header.resolution(), (Some(Resolution{pixels_per_meter: 2835}), None));
+        assert!((header.resolution().0.unwrap().dpi() - 72.0).abs() < 0.01);
+        assert_eq!(header.colors_important, 2);
+        assert!(header.is_top_down());
+    }
+
+    #[test]
+    fn test_read_adobe_headers() {
+        /* Two pixels with red in the low and in the high 16 bits. */
+        let data = [0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00];
+        let masks = [0x0000ffff, 0xffff0000u32, 0, 0];
+
+        for &(header_size, compression) in &[(52, 3), (56, 3), (56, 6)] {
+            let mut bmp = bmp_v3(2, 1, 32, compression, &[], &data);
+            let mut extra = Vec::new();
+            for mask in &masks[..(header_size - 40) / 4] {
+                extra.write_u32::<LittleEndian>(*mask).unwrap();
+            }
+            bmp.splice(54..54, extra);
+            (&mut bmp[10..14]).write_u32::<LittleEndian>(14 + header_size as u32).unwrap();
+            (&mut bmp[14..18]).write_u32::<LittleEndian>(header_size as u32).unwrap();
+
+            let header = probe(&mut Cursor::new(&bmp)).unwrap();
+            assert_eq!((header.red_mask, header.green_mask), (0x0000ffff, 0xffff0000));
+            assert_eq!(header.palette_offset, 14 + header_size as u64);
+            assert_eq!(read_red(bmp), vec![(0, 0, 0), (1, 0, 0xff)]);
+        }
+    }
+
+    #[test]
+    fn test_read_os2() {
+        let pallete = [[0, 0, 0, 0], [0, 0, 0xff, 0]];
+
+        for &header_size in &[16, 24, 64] {
+            let bmp = bmp_os2(header_size, 3, 1, 1, 0, &pallete, &[0b1010_0000, 0, 0, 0]);
+            assert_eq!(probe(&mut Cursor::new(&bmp)).unwrap().version, BMPVersion::OS2);
+            assert_eq!(read_red(bmp), vec![(0, 0, 0xff), (1, 0, 0), (2, 0, 0xff)]);
+        }
+
+        /* A declared palette size is honoured, a missing one is a full palette. */
+        let bmp = bmp_os2(64, 1, 1, 8, 0, &pallete, &[1, 0, 0, 0]);
+        assert_eq!(probe(&mut Cursor::new(&bmp)).unwrap().n_colors, 2);
+        let bmp = bmp_os2(16, 1, 1, 8, 0, &[[0, 0, 0, 0]; 256], &[1, 0, 0, 0]);
+        assert_eq!(probe(&mut Cursor::new(&bmp)).unwrap().n_colors, 256);
+
+        assert!(probe(&mut Cursor::new(bmp_os2(17, 1, 1, 1, 0, &pallete, &[0; 4]))).is_err());
+    }
+
+    #[test]
+    fn test_read_huffman() {
+        let pallete = [[0xff, 0xff, 0xff, 0], [0, 0, 0, 0]];
+        /* Each row is white 1, black 2, which reads back as red 0xff, 0, 0. */
+        let bmp = bmp_os2(64, 3, 2, 1, 3, &pallete, &[0b0001_1111, 0b0001_1111]);
+
+        assert_eq!(probe(&mut Cursor::new(&bmp)).unwrap().compression, CompressionType::Huffman1D);
+        assert_eq!(read_red(bmp), vec![(0, 0, 0xff), (1, 0, 0), (2, 0, 0),
+                                       (0, 1, 0xff), (1, 1, 0), (2, 1, 0)]);
+
+        let bmp = bmp_os2(64, 3, 1, 1, 3, &pallete, &[0, 0x80, 0]);
+        let mut source = Cursor::new(bmp);
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        match reader.decode_to_vec(PixelFormat::RGB8) {
+            Err(BMPError::InvalidHuffmanCode{row: 0, column: 0}) => (),
+            result => panic!("{:?}", result),
+        }
+
+        /* Outside OS/2 headers the same value means bitfields. */
+        match probe(&mut Cursor::new(bmp_v3(3, 1, 1, 3, &pallete, &[0; 4]))) {
+            Err(BMPError::BitfieldsNotSupportedForPixelDepth(1)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_read_rle24() {
+        let data = [2, 0, 0, 0x80, 0, 0,
+                    0, 3, 0, 0, 1, 0, 0, 2, 0, 0, 3, 0, 0, 1];
+        let bmp = bmp_os2(64, 3, 2, 24, 4, &[], &data);
+
+        assert_eq!(probe(&mut Cursor::new(&bmp)).unwrap().compression, CompressionType::RLE24);
+        assert_eq!(read_red(bmp), vec![(0, 0, 0x80), (1, 0, 0x80), (2, 0, 0),
+                                       (0, 1, 1), (1, 1, 2), (2, 1, 3)]);
+
+        /* Outside OS/2 headers the same value means an embedded JPEG. */
+        assert_eq!(probe(&mut Cursor::new(bmp_v3(3, 2, 24, 4, &[], &data))).unwrap().compression, CompressionType::JPEG);
+    }
+
+    /* A v3 file whose pixel data is an embedded JPEG or PNG image. */
+    fn bmp_embedded(width: i32, height: i32, compression: u32, image: &[u8]) -> Vec<u8> {
+        let mut bmp = bmp_v3(width, height, 0, compression, &[], image);
+        (&mut bmp[28..30]).write_u16::<LittleEndian>(0).unwrap();
+
+        bmp
+    }
+
+    #[test]
+    fn test_embedded_image() {
+        let image = b"\xff\xd8\xff\xe0 not really a JPEG";
+        let bmp = bmp_embedded(2, 2, 4, image);
+
+        let header = probe(&mut Cursor::new(&bmp)).unwrap();
+        assert_eq!(header.compression, CompressionType::JPEG);
+        assert_eq!(header.n_colors, 0);
+        assert_eq!(header.embedded_image(),
+                   Some(EmbeddedImage{format: EmbeddedFormat::JPEG, offset: 54, length: Some(image.len() as u64)}));
+        assert_eq!(&bmp[54..], &image[..]);
+
+        let mut source = Cursor::new(bmp);
+        match BMPReader::new(&mut source) {
+            Err(BMPError::EmbeddedImageNotSupported(EmbeddedFormat::JPEG)) => (),
+            _ => panic!(),
+        }
+
+        assert_eq!(probe(&mut Cursor::new(bmp_embedded(2, 2, 5, &[]))).unwrap().embedded_image().unwrap().format,
+                   EmbeddedFormat::PNG);
+        assert!(probe(&mut Cursor::new(bmp_v3(2, 2, 24, 0, &[], &[]))).unwrap().embedded_image().is_none());
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_read_embedded_png() {
+        let mut image = Vec::new();
+        {
+            let mut encoder = ::png::Encoder::new(&mut image, 2, 2);
+            encoder.set_color(::png::ColorType::Rgba);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0x10, 0, 0, 0xff,  0x20, 0, 0, 0x80,
+                                      0x30, 0, 0, 0xff,  0x40, 0, 0, 0]).unwrap();
+        }
+
+        for &height in &[2, -2] {
+            let mut source = Cursor::new(bmp_embedded(2, height, 5, &image));
+            let mut reader = BMPReader::new(&mut source).unwrap();
+            assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                       vec![0x10, 0, 0, 0xff,  0x20, 0, 0, 0x80,
+                            0x30, 0, 0, 0xff,  0x40, 0, 0, 0]);
+        }
+
+        let options = BMPOptions {
+            convert_calibrated_rgb: true,
+            ..BMPOptions::default()
+        };
+        let mut source = Cursor::new(with_calibrated_rgb(bmp_embedded(2, 2, 5, &image)));
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap()[..4], [0x10, 0, 0, 0xff]);
+
+        let mut source = Cursor::new(bmp_embedded(3, 2, 5, &image));
+        match BMPReader::new(&mut source) {
+            Err(BMPError::EmbeddedImageSizeMismatch{width: 2, height: 2, header_width: 3, header_height: 2}) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_read_64bpp() {
+        let mut data = Vec::new();
+        /* Blue 1.0, green 0.5, red -0.25 and alpha 2.0, then linear 0.18. */
+        for &value in &[8192, 4096, -2048, 16384, 1475, 1475, 1475, 8192] {
+            data.write_i16::<LittleEndian>(value).unwrap();
+        }
+        let bmp = bmp_v3(2, 1, 64, 0, &[], &data);
+
+        let mut source = Cursor::new(bmp.clone());
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                   vec![0, 0xbc, 0xff, 0xff,  0x76, 0x76, 0x76, 0xff]);
+
+        let mut source = Cursor::new(bmp);
+        let options = BMPOptions {
+            linear_64bpp: true,
+            ..BMPOptions::default()
+        };
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                   vec![0, 0x80, 0xff, 0xff,  0x2e, 0x2e, 0x2e, 0xff]);
+    }
+
+    #[test]
+    fn test_read_cmyk() {
+        /* Bytes are black, yellow, magenta, cyan: pure cyan, then half black. */
+        let data = [0, 0, 0, 0xff,  0x80, 0, 0, 0];
+        let bmp = bmp_v3(2, 1, 32, 11, &[], &data);
+
+        let mut source = Cursor::new(bmp.clone());
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!(reader.header().compression, CompressionType::CMYK);
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                   vec![0, 0xff, 0xff, 0xff,  0x7f, 0x7f, 0x7f, 0xff]);
+
+        let mut source = Cursor::new(bmp);
+        let options = BMPOptions {
+            raw_cmyk: true,
+            ..BMPOptions::default()
+        };
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                   vec![0xff, 0, 0, 0,  0, 0, 0, 0x80]);
+
+        /* CMYK palettes, here pure yellow and pure magenta. */
+        let pallete = [[0, 0xff, 0, 0], [0, 0, 0xff, 0]];
+        let bmp = bmp_v3(3, 1, 8, 12, &pallete, &[2, 1, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(probe_with_palette(&mut Cursor::new(&bmp)).unwrap().1[0],
+                   PalletePixel{red: 0xff, green: 0xff, blue: 0});
+        assert_eq!(read_red(bmp), vec![(0, 0, 0xff), (1, 0, 0xff), (2, 0, 0xff)]);
+
+        let bmp = bmp_v3(3, 1, 4, 13, &pallete, &[3, 0x01, 0, 0, 0, 1]);
+        let mut source = Cursor::new(bmp);
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGB8).unwrap(),
+                   vec![0xff, 0xff, 0,  0xff, 0, 0xff,  0xff, 0xff, 0]);
+
+        assert!(probe(&mut Cursor::new(bmp_v3(3, 1, 24, 11, &[], &[0; 12]))).is_err());
+    }
+
+    /* A v5 file of 3x2 pixels with 24 bits per pixel, whose pixel data is at
+     * bytes 138 to 162, followed by a profile. */
+    fn bmp_v5_with_profile(cs_type: &[u8; 4], profile_offset: u32, profile: &[u8]) -> Vec<u8> {
+        let mut bmp = Vec::new();
+        let pixels = vec![Pixel{red: 0, green: 0, blue: 0, alpha: !0}; 6];
+        BMPWriter::new(BMPVersion::Five, 24).unwrap().write(&mut bmp, 3, 2, &pixels).unwrap();
+
+        bmp[70..74].copy_from_slice(cs_type);
+        (&mut bmp[126..130]).write_u32::<LittleEndian>(profile_offset).unwrap();
+        (&mut bmp[130..134]).write_u32::<LittleEndian>(profile.len() as u32).unwrap();
+        bmp.extend_from_slice(profile);
+
+        bmp
+    }
+
+    #[test]
+    fn test_read_profile() {
+        let bmp = bmp_v5_with_profile(b"DEBM", 162 - 14, b"acsp profile");
+        let mut source = Cursor::new(bmp);
+        let header = probe(&mut source).unwrap();
+        assert_eq!(header.color_space, Some(ColorSpace::EmbeddedProfile));
+        assert_eq!(header.rendering_intent, Some(RenderingIntent::Perceptual));
+        assert_eq!(header.profile, Some(ProfileLocation{offset: 162, size: 12}));
+        assert_eq!(read_profile(&mut source, &header).unwrap(), Some(Profile::Embedded(b"acsp profile".to_vec())));
+
+        let bmp = bmp_v5_with_profile(b"KNIL", 162 - 14, b"C:\\sRGB.icc\0\0\0");
+        let mut source = Cursor::new(bmp);
+        let header = probe(&mut source).unwrap();
+        assert_eq!(read_profile(&mut source, &header).unwrap(), Some(Profile::Linked("C:\\sRGB.icc".to_string())));
+
+        let bmp = bmp_v5_with_profile(b"DEBM", 150 - 14, b"acsp profile");
+        let mut source = Cursor::new(bmp);
+        let header = probe(&mut source).unwrap();
+        match read_profile(&mut source, &header) {
+            Err(BMPError::ProfileOverlapsPixelData{offset: 150, size: 12}) => (),
+            result => panic!("{:?}", result),
+        }
+
+        /* The writer uses sRGB, so there is no profile to read. */
+        let bmp = bmp_v5_with_profile(b"BGRs", 162 - 14, b"acsp profile");
+        let mut source = Cursor::new(bmp);
+        let header = probe(&mut source).unwrap();
+        assert_eq!(header.profile, None);
+        assert_eq!(read_profile(&mut source, &header).unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_profile_rle() {
+        let mut writer = BMPWriter::new(BMPVersion::Five, 8).unwrap();
+        writer.set_compression(CompressionType::RLE8).unwrap();
+        let mut bmp = Vec::new();
+        writer.write_indexed(&mut bmp, 4, 2, &[PalletePixel{red: 0, green: 0, blue: 0}], &[0; 8]).unwrap();
+
+        bmp[70..74].copy_from_slice(b"DEBM");
+        let profile_offset = bmp.len() as u32 - 14;
+        (&mut bmp[126..130]).write_u32::<LittleEndian>(profile_offset).unwrap();
+        (&mut bmp[130..134]).write_u32::<LittleEndian>(12).unwrap();
+        bmp.extend_from_slice(b"acsp profile");
+
+        let header = probe(&mut Cursor::new(&bmp)).unwrap();
+        assert_eq!(read_profile(&mut Cursor::new(&bmp), &header).unwrap(), Some(Profile::Embedded(b"acsp profile".to_vec())));
+
+        /* Without biSizeImage the end of the pixel data is not known. */
+        (&mut bmp[34..38]).write_u32::<LittleEndian>(0).unwrap();
+        let header = probe(&mut Cursor::new(&bmp)).unwrap();
+        match read_profile(&mut Cursor::new(&bmp), &header) {
+            Err(BMPError::PixelDataSizeUnknown(CompressionType::RLE8)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    /* The sRGB primaries, with a gamma of 2.2. */
+    fn calibrated_color_space() -> Vec<u8> {
+        let mut color_space = Vec::new();
+        color_space.write_u32::<LittleEndian>(0).unwrap();
+        for &value in &[0.4124, 0.2126, 0.0193, 0.3576, 0.7152, 0.1192, 0.1805, 0.0722, 0.9505] {
+            color_space.write_u32::<LittleEndian>((value * (1 << 30) as f64) as u32).unwrap();
+        }
+        for _ in 0..3 {
+            color_space.write_u32::<LittleEndian>(0x23333).unwrap();
+        }
+
+        color_space
+    }
+
+    /* Turns a file from bmp_v3() into one with a v4 header in calibrated RGB. */
+    fn with_calibrated_rgb(mut bmp: Vec<u8>) -> Vec<u8> {
+        let mut extra = vec![0; 16];
+        extra.extend_from_slice(&calibrated_color_space());
+        bmp.splice(54..54, extra);
+
+        let file_size = bmp.len() as u32;
+        (&mut bmp[2..6]).write_u32::<LittleEndian>(file_size).unwrap();
+        (&mut bmp[10..14]).write_u32::<LittleEndian>(54 + 68).unwrap();
+        (&mut bmp[14..18]).write_u32::<LittleEndian>(108).unwrap();
+
+        bmp
+    }
+
+    #[test]
+    fn test_calibrated_rgb_not_applied() {
+        let options = BMPOptions {
+            convert_calibrated_rgb: true,
+            linear_64bpp: true,
+            raw_cmyk: true,
+            ..BMPOptions::default()
+        };
+
+        let mut data = Vec::new();
+        for &value in &[1475, 1475, 1475, 8192] {
+            data.write_i16::<LittleEndian>(value).unwrap();
+        }
+        let mut source = Cursor::new(with_calibrated_rgb(bmp_v3(1, 1, 64, 0, &[], &data)));
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(), vec![0x2e, 0x2e, 0x2e, 0xff]);
+
+        let mut source = Cursor::new(with_calibrated_rgb(bmp_v3(1, 1, 32, 11, &[], &[0x40; 4])));
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(), vec![0x40; 4]);
+
+        /* Without raw CMYK, the converted RGB values are calibrated. */
+        let options = BMPOptions {
+            raw_cmyk: false,
+            ..options
+        };
+        let mut source = Cursor::new(with_calibrated_rgb(bmp_v3(1, 1, 32, 11, &[], &[0x80, 0, 0, 0])));
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGB8).unwrap(), vec![0x80; 3]);
+    }
+
+    #[test]
+    fn test_calibrated_rgb() {
+        let pixels = [Pixel{red: 0x80808080, green: 0x80808080, blue: 0x80808080, alpha: !0},
+                      Pixel{red: !0, green: 0, blue: 0, alpha: !0}];
+        let mut bmp = Vec::new();
+        BMPWriter::new(BMPVersion::Four, 24).unwrap().write(&mut bmp, 2, 1, &pixels).unwrap();
+
+        bmp[70..122].copy_from_slice(&calibrated_color_space());
+
+        let options = BMPOptions {
+            convert_calibrated_rgb: true,
+            ..BMPOptions::default()
+        };
+        let mut source = Cursor::new(bmp.clone());
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGB8).unwrap(), vec![0x81, 0x81, 0x81,  0xff, 0, 0]);
+
+        /* With a gamma of 1.0, the values are linear light. */
+        for i in 0..3 {
+            (&mut bmp[110 + 4 * i..]).write_u32::<LittleEndian>(0x10000).unwrap();
+        }
+        let mut source = Cursor::new(bmp.clone());
+        let reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.map(|(_, _, px)| px.unwrap().green >> 24).collect::<Vec<_>>(), vec![0xbc, 0]);
+
+        let mut source = Cursor::new(bmp.clone());
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGB8).unwrap(), vec![0x80, 0x80, 0x80,  0xff, 0, 0]);
+
+        /* Zeroed endpoints leave the pixels alone rather than making them black. */
+        for byte in bmp[74..122].iter_mut() {
+            *byte = 0;
+        }
+        let mut source = Cursor::new(bmp);
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGB8).unwrap(), vec![0x80, 0x80, 0x80,  0xff, 0, 0]);
+    }
+
+    #[test]
+    fn test_huge_width() {
+        let mut bmp = bmp_v3(1, 1, 24, 0, &[], &[0; 4]);
+        (&mut bmp[18..22]).write_i32::<LittleEndian>(0x7fffffff).unwrap();
+
+        match BMPReader::new(&mut Cursor::new(bmp)) {
+            Err(BMPError::ImageTooLarge{width: 0x7fffffff, height: 1}) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_huge_pixel_depth() {
+        let mut bmp = bmp_v3(1, 1, 24, 0, &[], &[0; 4]);
+        (&mut bmp[28..30]).write_u16::<LittleEndian>(0xffff).unwrap();
+
+        match BMPReader::new(&mut Cursor::new(bmp.clone())) {
+            Err(BMPError::UnsupportedBitsPerPixel(0xffff)) => (),
+            _ => panic!(),
+        }
+
+        /* The row of pixel data, rather than of Pixels, is what is too large. */
+        (&mut bmp[18..22]).write_i32::<LittleEndian>(1_000_000).unwrap();
+        match BMPReader::new(&mut Cursor::new(bmp)) {
+            Err(BMPError::ImageTooLarge{width: 1_000_000, height: 1}) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_file_size() {
+        let bmp = bmp_v3(2, 1, 8, 0, &[[0, 0, 0xff, 0], [0; 4]], &[0, 1, 0, 0]);
+        assert_eq!(probe(&mut Cursor::new(&bmp)).unwrap().file_size, bmp.len() as u64);
+
+        /* A pixel offset past 64 KiB, with a gap after the palette. */
+        let mut far = bmp.clone();
+        far.splice(62..62, vec![0; 0x10000]);
+        (&mut far[2..6]).write_u32::<LittleEndian>(bmp.len() as u32 + 0x10000).unwrap();
+        (&mut far[10..14]).write_u32::<LittleEndian>(62 + 0x10000).unwrap();
+        assert_eq!(read_red(far), vec![(0, 0, 0xff), (1, 0, 0)]);
+
+        /* Trailing bytes, an unknown size and a size that is too large but
+         * leaves the pixel data whole are all fine. */
+        let mut longer = bmp.clone();
+        longer.push(0);
+        assert_eq!(read_red(longer), vec![(0, 0, 0xff), (1, 0, 0)]);
+
+        let mut unknown = bmp.clone();
+        (&mut unknown[2..6]).write_u32::<LittleEndian>(0).unwrap();
+        assert_eq!(read_red(unknown), vec![(0, 0, 0xff), (1, 0, 0)]);
+
+        let mut too_large = bmp.clone();
+        (&mut too_large[2..6]).write_u32::<LittleEndian>(1000).unwrap();
+        assert_eq!(read_red(too_large), vec![(0, 0, 0xff), (1, 0, 0)]);
+
+        /* A file cut short of its pixel data fails. Leniently, it is read as far
+         * as it goes, which here only misses the padding of the last row. */
+        let mut source = Cursor::new(&bmp[..bmp.len() - 1]);
+        match BMPReader::new(&mut source) {
+            Err(BMPError::FileSizeMismatch{declared: 66, actual: 65}) => (),
+            _ => panic!(),
+        }
+
+        let options = BMPOptions {
+            lenient_file_size: true,
+            ..BMPOptions::default()
+        };
+        assert_eq!(read_red_with_options(bmp[..bmp.len() - 1].to_vec(), options), vec![(0, 0, 0xff), (1, 0, 0)]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = [0x01, 0x02, 0x03,  0x04, 0x05, 0x06,  0, 0,
+                    0x07, 0x08, 0x09,  0x0a];
+
+        let mut source = Cursor::new(bmp_v3(2, 2, 24, 0, &[], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert!(reader.next().unwrap().2.is_ok());
+        assert!(reader.next().unwrap().2.is_ok());
+        match reader.next().unwrap() {
+            (1, 1, Err(BMPError::TruncatedPixelData{row: 1, column: 1})) => (),
+            _ => panic!(),
+        }
+        assert!(reader.next().is_none());
+
+        let data = [2, 0, 0];
+        let mut source = Cursor::new(bmp_v3(2, -2, 8, 1, &[[0; 4]], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        match reader.decode_to_vec(PixelFormat::RGBA8) {
+            Err(BMPError::TruncatedPixelData{row: 1, column: 2}) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_last_row_without_padding() {
+        let data = [0x01, 0x02, 0x03,  0x04, 0x05, 0x06];
+
+        let mut source = Cursor::new(bmp_v3(2, 1, 24, 0, &[], &data));
+        let mut reader = BMPReader::new(&mut source).unwrap();
+        assert!(reader.decode_to_vec(PixelFormat::RGBA8).is_ok());
+    }
+
+    #[test]
+    fn test_pallete_index_out_of_range() {
+        let pallete = [[0, 0, 0, 0], [0xff, 0xff, 0xff, 0]];
+        let data = [0, 2, 0, 0];
+
+        let mut source = Cursor::new(bmp_v3(2, 1, 8, 0, &pallete, &data));
+        let mut pixels = BMPReader::new(&mut source).unwrap().map(|(_, _, px)| px);
+        match pixels.next().unwrap() {
+            Err(BMPError::PaletteIndexOutOfRange{index: 2, palette_len: 2}) => (),
+            _ => panic!(),
+        }
+
+        let black = Pixel{red: 0, green: 0, blue: 0, alpha: 0xffffffff};
+        let options = BMPOptions{invalid_palette_index: InvalidPaletteIndex::Color(black), ..BMPOptions::default()};
+        let mut source = Cursor::new(bmp_v3(2, 1, 8, 0, &pallete, &data));
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                   vec![0, 0, 0, 0xff, 0, 0, 0, 0xff]);
+
+        let mut source = Cursor::new(bmp_v3(2, 1, 8, 1, &pallete, &[2, 5, 0, 1]));
+        let mut reader = BMPReader::with_options(&mut source, options).unwrap();
+        assert_eq!(reader.decode_to_vec(PixelFormat::RGBA8).unwrap(),
+                   vec![0, 0, 0, 0xff, 0, 0, 0, 0xff]);
+    }
+
+    /* Only implements Read, so probing must not seek. */
+    struct ReadOnly<'a>(&'a [u8]);
+
+    impl<'a> Read for ReadOnly<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_probe() {
+        let pallete = [[0x10, 0x20, 0x30, 0], [0x40, 0x50, 0x60, 0]];
+        let bmp = bmp_v3(5, 3, 1, 0, &pallete, &[]);
+
+        let header = probe(&mut ReadOnly(&bmp)).unwrap();
+        assert_eq!(header.width, 5);
+        assert_eq!(header.height, 3);
+        assert_eq!(header.palette_offset, 54);
+
+        let (header, pallete) = probe_with_palette(&mut ReadOnly(&bmp)).unwrap();
+        assert_eq!(header.n_colors, 2);
+        assert_eq!(pallete[1], PalletePixel{red: 0x60, green: 0x50, blue: 0x40});
+
+        assert!(probe(&mut ReadOnly(&bmp[..40])).is_err());
     }
 }
@@ -0,0 +1,255 @@
+use bmp_header::BMPError;
+use bmp_pixels::{Pixel,PalletePixel};
+
+type Color = [u8; 3];
+
+fn color(px: &Pixel) -> Color {
+    [(px.red >> 24) as u8, (px.green >> 24) as u8, (px.blue >> 24) as u8]
+}
+
+fn nearest(pallete: &[PalletePixel], color: [i32; 3]) -> usize {
+    let mut best = 0;
+    let mut best_distance = i32::MAX;
+
+    for (i, entry) in pallete.iter().enumerate() {
+        let distance = (entry.red as i32 - color[0]).pow(2) +
+                       (entry.green as i32 - color[1]).pow(2) +
+                       (entry.blue as i32 - color[2]).pow(2);
+
+        if distance < best_distance {
+            best = i;
+            best_distance = distance;
+        }
+    }
+
+    best
+}
+
+/* A set of distinct colours with the number of pixels having each of them. */
+struct ColorBox {
+    colors: Vec<(Color, u32)>,
+}
+
+impl ColorBox {
+    /* The channel with the widest range of values, and that range. */
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..3).map(|channel| {
+            let min = self.colors.iter().map(|&(color, _)| color[channel]).min().unwrap_or(0);
+            let max = self.colors.iter().map(|&(color, _)| color[channel]).max().unwrap_or(0);
+
+            (channel, max - min)
+        }).max_by_key(|&(_, range)| range).unwrap()
+    }
+
+    /* Splits the box at the median pixel along its widest channel. */
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let (channel, _) = self.widest_channel();
+        self.colors.sort_by_key(|&(color, _)| color[channel]);
+
+        let total: u64 = self.colors.iter().map(|&(_, count)| count as u64).sum();
+        let mut n_pixels = 0;
+        let mut at = 1;
+        for (i, &(_, count)) in self.colors.iter().enumerate() {
+            n_pixels += count as u64;
+            if n_pixels * 2 >= total {
+                at = i + 1;
+                break;
+            }
+        }
+        at = at.min(self.colors.len() - 1);
+
+        let upper = self.colors.split_off(at);
+        (self, ColorBox{colors: upper})
+    }
+
+    fn average(&self) -> PalletePixel {
+        let mut sums = [0u64; 3];
+        let mut total = 0u64;
+
+        for &(color, count) in &self.colors {
+            for (sum, &value) in sums.iter_mut().zip(color.iter()) {
+                *sum += value as u64 * count as u64;
+            }
+            total += count as u64;
+        }
+
+        let average = |sum: u64| ((sum + total / 2) / total.max(1)) as u8;
+        PalletePixel{red: average(sums[0]), green: average(sums[1]), blue: average(sums[2])}
+    }
+}
+
+/* Chooses a palette of at most n_colors entries, and never more than 256, for
+ * the pixels with the median cut algorithm. When there are no more distinct
+ * colours than that, they all get their own entry. Alpha is ignored, as
+ * palette entries are opaque. */
+pub fn median_cut(pixels: &[Pixel], n_colors: usize) -> Vec<PalletePixel> {
+    let n_colors = n_colors.min(256);
+    let mut colors: Vec<Color> = pixels.iter().map(color).collect();
+    colors.sort();
+
+    let mut counted: Vec<(Color, u32)> = Vec::new();
+    for color in colors {
+        match counted.last_mut() {
+            Some(last) if last.0 == color => last.1 += 1,
+            _ => counted.push((color, 1)),
+        }
+    }
+
+    if counted.is_empty() || n_colors == 0 {
+        return Vec::new();
+    }
+
+    let mut boxes = vec![ColorBox{colors: counted}];
+    while boxes.len() < n_colors {
+        let widest = boxes.iter().enumerate()
+                          .map(|(i, color_box)| (i, color_box.widest_channel().1))
+                          .max_by_key(|&(_, range)| range);
+
+        match widest {
+            Some((i, range)) if range > 0 => {
+                let (lower, upper) = boxes.swap_remove(i).split();
+                boxes.push(lower);
+                boxes.push(upper);
+            },
+            _ => break,
+        }
+    }
+
+    boxes.iter().map(ColorBox::average).collect()
+}
+
+/* Maps each pixel of an image width pixels wide, top row first, to the index
+ * of the closest palette entry. With dithering, the difference between each
+ * pixel and its entry is spread over the pixels to its right and below, as in
+ * Floyd-Steinberg dithering, so that areas average out to their true colour.
+ * Palettes of more than 256 entries are rejected, as their indices do not fit
+ * a byte. */
+pub fn map_to_palette(pixels: &[Pixel], width: usize, pallete: &[PalletePixel], dither: bool) -> Result<Vec<u8>, BMPError> {
+    if pallete.len() > 256 {
+        return Err(BMPError::PaletteTooLarge(pallete.len(), 8));
+    }
+
+    Ok(map_indices(pixels, width, pallete, dither))
+}
+
+fn map_indices(pixels: &[Pixel], width: usize, pallete: &[PalletePixel], dither: bool) -> Vec<u8> {
+    if !dither || width == 0 || pallete.is_empty() {
+        return pixels.iter().map(|px| {
+            let [red, green, blue] = color(px);
+            nearest(pallete, [red as i32, green as i32, blue as i32]) as u8
+        }).collect();
+    }
+
+    /* Errors for the current and next row, with a column of room on each side. */
+    let mut errors = vec![[0i32; 3]; width + 2];
+    let mut next_errors = vec![[0i32; 3]; width + 2];
+    let mut indices = Vec::with_capacity(pixels.len());
+
+    for row in pixels.chunks(width) {
+        for (x, px) in row.iter().enumerate() {
+            let mut target = [0; 3];
+            for (channel, &value) in color(px).iter().enumerate() {
+                target[channel] = (value as i32 + errors[x + 1][channel] / 16).clamp(0, 255);
+            }
+
+            let index = nearest(pallete, target);
+            let entry = pallete[index];
+            let chosen = [entry.red as i32, entry.green as i32, entry.blue as i32];
+
+            for channel in 0..3 {
+                let error = target[channel] - chosen[channel];
+
+                errors[x + 2][channel] += error * 7;
+                next_errors[x][channel] += error * 3;
+                next_errors[x + 1][channel] += error * 5;
+                next_errors[x + 2][channel] += error;
+            }
+
+            indices.push(index as u8);
+        }
+
+        errors = next_errors;
+        next_errors = vec![[0i32; 3]; width + 2];
+    }
+
+    indices
+}
+
+/* Generates a palette of at most n_colors entries for the pixels of an image
+ * width pixels wide, and returns it with one palette index per pixel. */
+pub fn quantize(pixels: &[Pixel], width: usize, n_colors: usize, dither: bool) -> (Vec<PalletePixel>, Vec<u8>) {
+    let pallete = median_cut(pixels, n_colors);
+    let indices = map_indices(pixels, width, &pallete, dither);
+
+    (pallete, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(red: u8, green: u8, blue: u8) -> Pixel {
+        Pixel {
+            red: red as u32 * 0x01010101,
+            green: green as u32 * 0x01010101,
+            blue: blue as u32 * 0x01010101,
+            alpha: !0,
+        }
+    }
+
+    #[test]
+    fn test_few_colors_are_kept() {
+        let pixels = [rgb(0xff, 0, 0), rgb(0, 0, 0xff), rgb(0xff, 0, 0), rgb(1, 2, 3)];
+        let (pallete, indices) = quantize(&pixels, 2, 16, true);
+
+        assert_eq!(pallete.len(), 3);
+        for (px, &index) in pixels.iter().zip(indices.iter()) {
+            let entry = pallete[index as usize];
+            assert_eq!(rgb(entry.red, entry.green, entry.blue), *px);
+        }
+    }
+
+    #[test]
+    fn test_median_cut() {
+        let centers = [[0xc0, 0x20, 0x20], [0x20, 0xc0, 0x20], [0x20, 0x20, 0xc0], [0xe0, 0xe0, 0xe0]];
+        let pixels: Vec<Pixel> = centers.iter().flat_map(|center| {
+            (0..16).map(move |i| rgb(center[0] + i % 4, center[1] + i / 4, center[2]))
+        }).collect();
+        let pallete = median_cut(&pixels, 4);
+
+        assert_eq!(pallete.len(), 4);
+        for center in &centers {
+            let index = nearest(&pallete, [center[0] as i32, center[1] as i32, center[2] as i32]);
+            let entry = pallete[index];
+            assert!((entry.red as i32 - center[0] as i32 - 2).abs() <= 1);
+            assert!((entry.green as i32 - center[1] as i32 - 2).abs() <= 1);
+            assert_eq!(entry.blue, center[2]);
+        }
+    }
+
+    #[test]
+    fn test_dithering() {
+        let pallete = [PalletePixel{red: 0, green: 0, blue: 0},
+                       PalletePixel{red: 0xff, green: 0xff, blue: 0xff}];
+        let pixels = vec![rgb(0x80, 0x80, 0x80); 64];
+
+        let plain = map_to_palette(&pixels, 8, &pallete, false).unwrap();
+        assert!(plain.iter().all(|&index| index == plain[0]));
+
+        let dithered = map_to_palette(&pixels, 8, &pallete, true).unwrap();
+        let n_white = dithered.iter().filter(|&&index| index == 1).count();
+        assert!((28..=36).contains(&n_white));
+    }
+
+    #[test]
+    fn test_at_most_256_colors() {
+        let pixels: Vec<Pixel> = (0..512).map(|i| rgb((i % 256) as u8, (i / 256) as u8 * 0xff, 0)).collect();
+        assert_eq!(median_cut(&pixels, 512).len(), 256);
+
+        let pallete = vec![PalletePixel{red: 0, green: 0, blue: 0}; 257];
+        match map_to_palette(&pixels, 16, &pallete, false) {
+            Err(BMPError::PaletteTooLarge(257, 8)) => (),
+            _ => panic!(),
+        }
+    }
+}
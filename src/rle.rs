@@ -0,0 +1,388 @@
+use byteorder::ReadBytesExt;
+use std::io::{self,Read};
+
+#[derive(Copy,Clone)]
+pub enum RLEMode {
+    Four,
+    Eight,
+    /* OS/2 24-bit RLE, where each value is three bytes in BGR order. */
+    TwentyFour,
+}
+
+fn nibble(byte: u8, i: usize) -> u8 {
+    if i % 2 == 0 {
+        byte >> 4
+    } else {
+        byte & 0x0f
+    }
+}
+
+/* Expands run-length encoded pixel data into whole scanlines.
+ *
+ * The encoding is a sequence of two-byte commands. A non-zero first byte is an
+ * encoded run repeating the pixel value in the second byte, otherwise the
+ * second byte is an escape: 0 ends the line, 1 ends the bitmap, 2 moves the
+ * cursor right and down by the following two bytes, and any larger value is
+ * the number of pixels stored verbatim after it, padded to a 16-bit boundary.
+ * With four bits per pixel, runs alternate between the high and the low nibble
+ * of their value byte. With 24 bits per pixel, the value of an encoded run and
+ * each verbatim pixel take three bytes, and values are returned as 0xRRGGBB.
+ *
+ * Pixels that are not written by any command are left undefined. */
+pub struct RLEDecoder<'a, R: Read + 'a> {
+    source: &'a mut R,
+    mode: RLEMode,
+    row: Vec<Option<u32>>,
+    x: usize,
+    next_x: usize,
+    n_skipped_rows: usize,
+    end_of_bitmap: bool,
+}
+
+impl<'a, R: Read + 'a> RLEDecoder<'a, R> {
+    pub fn new(source: &'a mut R, mode: RLEMode, width: usize) -> RLEDecoder<'a, R> {
+        RLEDecoder {
+            source,
+            mode,
+            row: vec![None; width],
+            x: 0,
+            next_x: 0,
+            n_skipped_rows: 0,
+            end_of_bitmap: false,
+        }
+    }
+
+    pub fn read_row(&mut self) -> Result<&[Option<u32>], io::Error> {
+        self.decode_row()?;
+
+        Ok(&self.row)
+    }
+
+    /* The column of the row being decoded that the next pixel goes to. */
+    pub fn column(&self) -> usize {
+        self.x.min(self.row.len())
+    }
+
+    fn read_bgr(&mut self) -> Result<u32, io::Error> {
+        let mut bgr = [0; 3];
+        self.source.read_exact(&mut bgr)?;
+
+        Ok((bgr[2] as u32) << 16 | (bgr[1] as u32) << 8 | bgr[0] as u32)
+    }
+
+    fn put(&mut self, value: u32) {
+        if self.x < self.row.len() {
+            self.row[self.x] = Some(value);
+        }
+        self.x += 1;
+    }
+
+    fn decode_row(&mut self) -> Result<(), io::Error> {
+        for value in self.row.iter_mut() {
+            *value = None;
+        }
+
+        if self.end_of_bitmap {
+            return Ok(());
+        }
+
+        if self.n_skipped_rows > 0 {
+            self.n_skipped_rows -= 1;
+            return Ok(());
+        }
+
+        self.x = self.next_x;
+        self.next_x = 0;
+
+        loop {
+            let count = self.source.read_u8()? as usize;
+
+            if count > 0 {
+                match self.mode {
+                    RLEMode::Four => {
+                        let value = self.source.read_u8()?;
+                        for i in 0..count {
+                            self.put(nibble(value, i) as u32);
+                        }
+                    },
+                    RLEMode::Eight => {
+                        let value = self.source.read_u8()?;
+                        for _ in 0..count {
+                            self.put(value as u32);
+                        }
+                    },
+                    RLEMode::TwentyFour => {
+                        let value = self.read_bgr()?;
+                        for _ in 0..count {
+                            self.put(value);
+                        }
+                    },
+                }
+                continue;
+            }
+
+            match self.source.read_u8()? {
+                0 => break,
+                1 => {
+                    self.end_of_bitmap = true;
+                    break;
+                },
+                2 => {
+                    self.x += self.source.read_u8()? as usize;
+                    let dy = self.source.read_u8()? as usize;
+
+                    if dy > 0 {
+                        self.n_skipped_rows = dy - 1;
+                        self.next_x = self.x;
+                        break;
+                    }
+                },
+                count => {
+                    let count = count as usize;
+
+                    match self.mode {
+                        RLEMode::Four => {
+                            let n_bytes = count.div_ceil(2);
+                            let mut value = 0;
+
+                            for i in 0..count {
+                                if i % 2 == 0 {
+                                    value = self.source.read_u8()?;
+                                }
+                                self.put(nibble(value, i) as u32);
+                            }
+
+                            if n_bytes % 2 != 0 {
+                                self.source.read_u8()?;
+                            }
+                        },
+                        RLEMode::Eight => {
+                            for _ in 0..count {
+                                let value = self.source.read_u8()?;
+                                self.put(value as u32);
+                            }
+
+                            if count % 2 != 0 {
+                                self.source.read_u8()?;
+                            }
+                        },
+                        RLEMode::TwentyFour => {
+                            for _ in 0..count {
+                                let value = self.read_bgr()?;
+                                self.put(value);
+                            }
+
+                            if count % 2 != 0 {
+                                self.source.read_u8()?;
+                            }
+                        },
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/* The number of pixels from i on that one encoded run can reproduce. */
+fn run_length(mode: RLEMode, values: &[u8], i: usize) -> usize {
+    let mut n = 1;
+
+    while i + n < values.len() && n < 255 {
+        let matches = match mode {
+            RLEMode::Four => values[i + n] == values[i + n % 2],
+            _ => values[i + n] == values[i],
+        };
+        if !matches {
+            break;
+        }
+        n += 1;
+    }
+
+    n
+}
+
+/* Appends one scanline of pixel values to out, without the end of line or end
+ * of bitmap marker.
+ *
+ * Runs that are long enough to save space become encoded runs, and the pixels
+ * between them are stored verbatim in absolute runs, except for one or two
+ * pixels which are cheaper as short encoded runs. The values are bytes, so
+ * only RLE4 and RLE8 can be encoded. */
+pub fn encode_row(mode: RLEMode, values: &[u8], out: &mut Vec<u8>) {
+    let min_run = match mode {
+        RLEMode::Four => 5,
+        _ => 3,
+    };
+
+    let mut i = 0;
+    while i < values.len() {
+        let n = run_length(mode, values, i);
+        if n >= min_run {
+            out.push(n as u8);
+            out.push(run_value(mode, values, i, n));
+            i += n;
+            continue;
+        }
+
+        let mut end = i + 1;
+        while end < values.len() && end - i < 255 && run_length(mode, values, end) < min_run {
+            end += 1;
+        }
+
+        let literal = &values[i..end];
+        if literal.len() < 3 {
+            match mode {
+                RLEMode::Four => {
+                    out.push(literal.len() as u8);
+                    out.push(run_value(mode, literal, 0, literal.len()));
+                },
+                _ => {
+                    for &value in literal {
+                        out.extend_from_slice(&[1, value]);
+                    }
+                },
+            }
+        } else {
+            out.extend_from_slice(&[0, literal.len() as u8]);
+
+            let start = out.len();
+            match mode {
+                RLEMode::Four => {
+                    for pair in literal.chunks(2) {
+                        out.push(pair[0] << 4 | pair.get(1).map_or(0, |&value| value & 0x0f));
+                    }
+                },
+                _ => out.extend_from_slice(literal),
+            }
+
+            if (out.len() - start) % 2 != 0 {
+                out.push(0);
+            }
+        }
+
+        i = end;
+    }
+}
+
+fn run_value(mode: RLEMode, values: &[u8], i: usize, n: usize) -> u8 {
+    match mode {
+        RLEMode::Four if n > 1 => values[i] << 4 | (values[i + 1] & 0x0f),
+        RLEMode::Four => values[i] << 4,
+        _ => values[i],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn decode(mode: RLEMode, width: usize, height: usize, data: Vec<u8>) -> Vec<Option<u32>> {
+        let mut source = Cursor::new(data);
+        let mut decoder = RLEDecoder::new(&mut source, mode, width);
+        let mut values = Vec::new();
+
+        for _ in 0..height {
+            values.extend_from_slice(decoder.read_row().unwrap());
+        }
+
+        values
+    }
+
+    #[test]
+    fn test_rle8_runs() {
+        let data = vec![3, 7, 0, 3, 1, 2, 3, 0, 0, 0,
+                        0, 4, 9, 8, 7, 6, 2, 5, 0, 1];
+
+        assert_eq!(decode(RLEMode::Eight, 6, 2, data),
+                   [7, 7, 7, 1, 2, 3,
+                    9, 8, 7, 6, 5, 5].iter().map(|&value| Some(value)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rle8_delta_and_end_of_bitmap() {
+        let data = vec![1, 4, 0, 2, 2, 2, 2, 5, 0, 1];
+
+        assert_eq!(decode(RLEMode::Eight, 4, 4, data),
+                   vec![Some(4), None, None, None,
+                        None, None, None, None,
+                        None, None, None, Some(5),
+                        None, None, None, None]);
+    }
+
+    #[test]
+    fn test_rle4_runs() {
+        let data = vec![5, 0x12, 0, 0,
+                        0, 3, 0x34, 0x50, 2, 0x66, 0, 1];
+
+        assert_eq!(decode(RLEMode::Four, 5, 2, data),
+                   [1, 2, 1, 2, 1,
+                    3, 4, 5, 6, 6].iter().map(|&value| Some(value)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rle4_absolute_padding() {
+        let data = vec![0, 5, 0x12, 0x34, 0x50, 0, 0, 0,
+                        1, 0x70, 0, 1];
+
+        assert_eq!(decode(RLEMode::Four, 5, 2, data),
+                   vec![Some(1), Some(2), Some(3), Some(4), Some(5),
+                        Some(7), None, None, None, None]);
+    }
+
+    #[test]
+    fn test_rle24() {
+        let data = vec![2, 0x30, 0x20, 0x10, 0, 3, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 0, 0,
+                        0, 1];
+
+        assert_eq!(decode(RLEMode::TwentyFour, 5, 2, data),
+                   vec![Some(0x102030), Some(0x102030), Some(0x030201), Some(0x060504), Some(0x090807),
+                        None, None, None, None, None]);
+    }
+
+    fn round_trip(mode: RLEMode, values: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        encode_row(mode, values, &mut data);
+        data.extend_from_slice(&[0, 1]);
+
+        let decoded = decode(mode, values.len(), 1, data.clone());
+        assert_eq!(decoded, values.iter().map(|&value| Some(value as u32)).collect::<Vec<_>>());
+
+        data
+    }
+
+    #[test]
+    fn test_rle8_encode() {
+        assert_eq!(round_trip(RLEMode::Eight, &[7, 7, 7, 7, 1, 2, 3, 4, 4, 4, 9]),
+                   vec![4, 7, 0, 3, 1, 2, 3, 0, 3, 4, 1, 9, 0, 1]);
+        assert_eq!(round_trip(RLEMode::Eight, &[5; 300]),
+                   vec![255, 5, 45, 5, 0, 1]);
+
+        let values = (0..600).map(|i| (i * 7 % 13) as u8).collect::<Vec<_>>();
+        round_trip(RLEMode::Eight, &values);
+    }
+
+    #[test]
+    fn test_rle4_encode() {
+        assert_eq!(round_trip(RLEMode::Four, &[1, 2, 1, 2, 1, 2, 3, 4, 5]),
+                   vec![6, 0x12, 0, 3, 0x34, 0x50, 0, 1]);
+        assert_eq!(round_trip(RLEMode::Four, &[1, 2, 3]),
+                   vec![0, 3, 0x12, 0x30, 0, 1]);
+        assert_eq!(round_trip(RLEMode::Four, &[6, 7]),
+                   vec![2, 0x67, 0, 1]);
+
+        let values = (0..600).map(|i| (i * 5 % 11) as u8).collect::<Vec<_>>();
+        round_trip(RLEMode::Four, &values);
+    }
+
+    #[test]
+    fn test_rle8_truncated() {
+        let mut source = Cursor::new(vec![2, 1, 0]);
+        let mut decoder = RLEDecoder::new(&mut source, RLEMode::Eight, 4);
+
+        assert!(decoder.read_row().is_err());
+        assert_eq!(decoder.column(), 2);
+    }
+}